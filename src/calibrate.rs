@@ -0,0 +1,270 @@
+// Breath calibration.  The player is guided through three windows of equal
+// length, each announced by a beep:
+//
+//   1. Rest: no blowing.  The mean reading is the baseline, and the largest
+//      distance from it sets the noise floor.
+//   2. Soft: blow as softly as you would play.  Gives the quietest level.
+//   3. Full: blow as hard as you can.  Gives the level for full volume.
+//
+// The levels are raw sensor counts above the baseline.  The baseline replaces
+// the one measured at startup, and the levels the dead zone and ceiling of the
+// pressure configuration.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{PressureConfig, TimingConfig};
+use crate::sink::{beep, Event, RecordingSink};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    // Missing from calibrations saved before it was measured
+    #[serde(default)]
+    pub baseline: Option<i32>,
+    pub noise_floor: i32,
+    pub min: i32,
+    pub max: i32,
+}
+
+impl Calibration {
+    /// Replace the baseline, dead zone and ceiling with the calibrated values.
+    pub fn apply(&self, config: &mut PressureConfig) {
+        if self.baseline.is_some() {
+            config.baseline = self.baseline;
+        }
+        // Soft playing must always sound, even with a noisy sensor
+        config.dead_zone = self.noise_floor.min(self.min / 2);
+        config.ceiling = self.max;
+    }
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(baseline) = self.baseline {
+            write!(f, "baseline {}, ", baseline)?;
+        }
+        write!(
+            f,
+            "noise floor {}, soft {}, full {}",
+            self.noise_floor, self.min, self.max
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Rest,
+    Soft,
+    Full,
+    Done,
+}
+
+impl Phase {
+    /// What the player is expected to do during this phase.
+    pub fn prompt(&self) -> &'static str {
+        match self {
+            Phase::Rest => "Calibrating: do not blow...",
+            Phase::Soft => "Calibrating: blow softly...",
+            Phase::Full => "Calibrating: blow as hard as you can...",
+            Phase::Done => "Calibration finished",
+        }
+    }
+}
+
+/// Calibration state machine, fed one raw pressure reading per scan tick.
+pub struct Calibrator {
+    phase: Phase,
+    window: u32,
+    samples: Vec<i32>,
+    baseline: i32,
+    noise_floor: i32,
+    min: i32,
+    outcome: Option<Result<Calibration, String>>,
+}
+
+impl Calibrator {
+    pub fn new(timing: &TimingConfig) -> Self {
+        Calibrator {
+            phase: Phase::Rest,
+            window: timing.ms_to_ticks(timing.calibration_window_ms).max(1),
+            samples: Vec::new(),
+            baseline: 0,
+            noise_floor: 0,
+            min: 0,
+            outcome: None,
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// The calibration, or why it failed, once finished.
+    pub fn outcome(&self) -> Option<Result<Calibration, String>> {
+        self.outcome.clone()
+    }
+
+    /// Process one raw reading and return the prompt beeps to play.
+    pub fn tick(&mut self, raw: i32) -> Vec<Event> {
+        let mut out = RecordingSink::new();
+        if self.phase == Phase::Done {
+            return out.events;
+        }
+        if self.samples.is_empty() && self.phase == Phase::Rest {
+            beep(&mut out, 60, 50);
+        }
+        self.samples.push(raw);
+        if self.samples.len() < self.window as usize {
+            return out.events;
+        }
+
+        let samples = std::mem::take(&mut self.samples);
+        match self.phase {
+            Phase::Rest => {
+                let sum: i64 = samples.iter().map(|&s| s as i64).sum();
+                self.baseline = (sum as f64 / samples.len() as f64).round() as i32;
+                self.noise_floor = samples
+                    .iter()
+                    .map(|s| (s - self.baseline).abs())
+                    .max()
+                    .unwrap_or(0);
+                self.next_phase(Phase::Soft, &[67], &mut out);
+            }
+            Phase::Soft => match self.level(samples, 50) {
+                Some(min) => {
+                    self.min = min;
+                    self.next_phase(Phase::Full, &[72], &mut out);
+                }
+                None => self.fail("no breath detected, blow softly when prompted", &mut out),
+            },
+            Phase::Full => match self.level(samples, 95) {
+                Some(max) if max > self.min => {
+                    self.outcome = Some(Ok(Calibration {
+                        baseline: Some(self.baseline),
+                        noise_floor: self.noise_floor,
+                        min: self.min,
+                        max,
+                    }));
+                    self.next_phase(Phase::Done, &[72, 79], &mut out);
+                }
+                _ => self.fail("full strength is not above soft level", &mut out),
+            },
+            Phase::Done => {}
+        }
+        out.events
+    }
+
+    // Percentile of the readings above the noise floor, if the player blew
+    // for at least a quarter of the window.
+    fn level(&self, samples: Vec<i32>, percentile: usize) -> Option<i32> {
+        let mut blowing: Vec<i32> = samples
+            .into_iter()
+            .map(|s| s - self.baseline)
+            .filter(|&s| s > self.noise_floor)
+            .collect();
+        if blowing.len() < self.window as usize / 4 || blowing.is_empty() {
+            return None;
+        }
+        blowing.sort_unstable();
+        Some(blowing[(blowing.len() - 1) * percentile / 100])
+    }
+
+    fn next_phase(&mut self, phase: Phase, notes: &[i32], out: &mut RecordingSink) {
+        self.phase = phase;
+        for &note in notes {
+            beep(out, note, 50);
+        }
+    }
+
+    fn fail(&mut self, reason: &str, out: &mut RecordingSink) {
+        self.phase = Phase::Done;
+        self.outcome = Some(Err(format!("Calibration failed: {}", reason)));
+        beep(out, 48, 50);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(calibrator: &mut Calibrator, readings: &[i32]) -> Vec<Event> {
+        let mut events = Vec::new();
+        for &raw in readings {
+            events.extend(calibrator.tick(raw));
+        }
+        events
+    }
+
+    fn timing() -> TimingConfig {
+        TimingConfig {
+            calibration_window_ms: 40,
+            ..TimingConfig::default()
+        }
+    }
+
+    #[test]
+    fn calibrate() {
+        let mut calibrator = Calibrator::new(&timing());
+        // 20 ticks per window
+        let events = run(&mut calibrator, &[3, -4, 2, 0, 1].repeat(4));
+        assert_eq!(calibrator.phase(), Phase::Soft);
+        assert!(events.contains(&Event::NoteOn { note: 60, vel: 50 }));
+        assert!(events.contains(&Event::NoteOn { note: 67, vel: 50 }));
+
+        run(
+            &mut calibrator,
+            &[0, 0, 0, 0, 50, 60, 70, 60, 60, 60].repeat(2),
+        );
+        assert_eq!(calibrator.phase(), Phase::Full);
+
+        let mut full: Vec<i32> = (0..19).map(|i| 300 + i * 10).collect();
+        full.push(2000);
+        let events = run(&mut calibrator, &full);
+        assert!(events.contains(&Event::NoteOn { note: 79, vel: 50 }));
+        let calibration = calibrator.outcome().unwrap().unwrap();
+        assert_eq!(
+            calibration,
+            Calibration {
+                baseline: Some(0),
+                noise_floor: 4,
+                min: 60,
+                max: 480,
+            }
+        );
+
+        let mut config = PressureConfig::default();
+        calibration.apply(&mut config);
+        assert_eq!((config.dead_zone, config.ceiling), (4, 480));
+        assert_eq!(config.baseline, Some(0));
+    }
+
+    #[test]
+    fn baseline() {
+        // Levels are measured from the resting mean, not from zero
+        let mut calibrator = Calibrator::new(&timing());
+        let rest: Vec<i32> = [3, -4, 2, 0, 1].repeat(4).iter().map(|s| s + 30).collect();
+        run(&mut calibrator, &rest);
+        run(&mut calibrator, &[90; 20]);
+        run(&mut calibrator, &[530; 20]);
+        assert_eq!(
+            calibrator.outcome().unwrap().unwrap(),
+            Calibration {
+                baseline: Some(30),
+                noise_floor: 4,
+                min: 60,
+                max: 500,
+            }
+        );
+    }
+
+    #[test]
+    fn no_breath() {
+        let mut calibrator = Calibrator::new(&timing());
+        run(&mut calibrator, &[2; 40]);
+        assert_eq!(calibrator.phase(), Phase::Done);
+        assert!(calibrator.outcome().unwrap().is_err());
+        // Nothing more happens once done
+        assert_eq!(calibrator.tick(100), vec![]);
+    }
+}
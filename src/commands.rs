@@ -1,69 +1,125 @@
 use std::cmp::{max, min};
-use std::thread;
-use std::time::Duration;
 
-use fluidsynth::synth::Synth;
 use log::info;
 
+use crate::config::MAX_GAIN;
+use crate::instrument::Action;
+use crate::keys::{mask, Key};
+use crate::keyscan::{KeyEvent, KeyScan};
+use crate::sink::{beep, SoundSink};
+
 #[derive(Copy, Clone, PartialEq)]
 enum CommandKeys {
     ChangeProgUp,
     ChangeProgFastUp,
     ChangeProgDown,
     ChangeProgFastDown,
+    VolumeUp,
+    VolumeDown,
+    Calibrate,
+    NextProfile,
+    PreviousProfile,
     Unmapped,
 }
 
-const MIDI_CC_VOLUME: i32 = 7;
+const PROG_UP: u32 = Key::F.mask();
+const PROG_FAST_UP: u32 = mask(&[Key::F, Key::E]);
+const PROG_DOWN: u32 = Key::D.mask();
+const PROG_FAST_DOWN: u32 = mask(&[Key::E, Key::D]);
+// Left hand, as the program keys on the right
+const VOLUME_UP: u32 = Key::B.mask();
+const VOLUME_DOWN: u32 = Key::G.mask();
+// Synth gain change for each press
+const GAIN_STEP: f32 = 0.1;
+// Out of the way of the program change keys
+const CALIBRATE: u32 = Key::LowCSharp.mask();
+// Right hand pinky keys, Eb above C as on the instrument
+const NEXT_PROFILE: u32 = Key::LowEb.mask();
+const PREVIOUS_PROFILE: u32 = Key::LowC.mask();
 
 fn key2cmdkey(key: u32) -> CommandKeys {
     match key {
-        0x10000 => CommandKeys::ChangeProgUp,
-        0x90000 => CommandKeys::ChangeProgFastUp,
-        0x400000 => CommandKeys::ChangeProgDown,
-        0x480000 => CommandKeys::ChangeProgFastDown,
-        // 0x800000=> CommandKeys::ChangeVolume,
+        PROG_UP => CommandKeys::ChangeProgUp,
+        PROG_FAST_UP => CommandKeys::ChangeProgFastUp,
+        PROG_DOWN => CommandKeys::ChangeProgDown,
+        PROG_FAST_DOWN => CommandKeys::ChangeProgFastDown,
+        VOLUME_UP => CommandKeys::VolumeUp,
+        VOLUME_DOWN => CommandKeys::VolumeDown,
+        CALIBRATE => CommandKeys::Calibrate,
+        NEXT_PROFILE => CommandKeys::NextProfile,
+        PREVIOUS_PROFILE => CommandKeys::PreviousProfile,
         _ => CommandKeys::Unmapped,
     }
 }
 
-pub(crate) struct Command<'a> {
-    synth: &'a Synth,
+pub(crate) struct Command {
     prog_number: i32,
-    last_cmd_key: CommandKeys,
+    gain: f32,
 }
 
-impl<'a> Command<'a> {
-    pub(crate) fn new(synth: &'a Synth, prog_number: i32) -> Self {
+impl Command {
+    pub(crate) fn new(prog_number: i32, gain: f32) -> Self {
         Command {
-            synth: synth,
             prog_number: prog_number,
-            last_cmd_key: CommandKeys::Unmapped,
+            gain,
         }
     }
-    pub(crate) fn process(self: &mut Self, key: u32) {
-        let cmd_key = key2cmdkey(key);
-        if cmd_key == self.last_cmd_key {
-            return;
+    pub(crate) fn prog_number(&self) -> i32 {
+        self.prog_number
+    }
+    pub(crate) fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Handle the keys pressed in control mode.  A command runs when a key
+    /// press completes its keys, not when keys are let go.  Commands that
+    /// cannot be carried out here are returned to the caller.
+    pub(crate) fn process(
+        self: &mut Self,
+        scan: &KeyScan,
+        sink: &mut dyn SoundSink,
+    ) -> Option<Action> {
+        let pressed = scan
+            .events
+            .iter()
+            .any(|event| matches!(event, KeyEvent::Pressed(_)));
+        if !pressed {
+            return None;
         }
-        self.last_cmd_key = cmd_key;
 
-        match cmd_key {
-            CommandKeys::ChangeProgUp => self.change_program(1),
-            CommandKeys::ChangeProgFastUp => self.change_program(10),
-            CommandKeys::ChangeProgDown => self.change_program(-1),
-            CommandKeys::ChangeProgFastDown => self.change_program(-10),
+        match key2cmdkey(scan.keys) {
+            CommandKeys::ChangeProgUp => self.change_program(1, sink),
+            CommandKeys::ChangeProgFastUp => self.change_program(10, sink),
+            CommandKeys::ChangeProgDown => self.change_program(-1, sink),
+            CommandKeys::ChangeProgFastDown => self.change_program(-10, sink),
+            CommandKeys::VolumeUp => self.change_gain(GAIN_STEP, sink),
+            CommandKeys::VolumeDown => self.change_gain(-GAIN_STEP, sink),
+            CommandKeys::Calibrate => return Some(Action::Calibrate),
+            CommandKeys::NextProfile => return Some(Action::SwitchProfile(1)),
+            CommandKeys::PreviousProfile => return Some(Action::SwitchProfile(-1)),
             _ => (),
         };
+        None
     }
 
-    fn change_program(self: &mut Self, change: i32) {
+    fn change_program(self: &mut Self, change: i32, sink: &mut dyn SoundSink) {
         self.prog_number = max(0, min(127, self.prog_number + change));
-        self.synth.program_change(0, self.prog_number);
+        sink.program_change(self.prog_number);
         info!("New MIDI program number {}", self.prog_number);
-        self.synth.noteon(0, 53, 60);
-        self.synth.cc(0, MIDI_CC_VOLUME, 60);
-        thread::sleep(Duration::from_millis(100));
-        self.synth.noteoff(0, 53);
+        sink.noteon(53, 60);
+        sink.breath(60);
+        sink.pause(100);
+        sink.noteoff(53);
+    }
+
+    fn change_gain(&mut self, change: f32, sink: &mut dyn SoundSink) {
+        // Whole steps, without rounding errors piling up
+        self.gain = ((self.gain + change) * 10.0)
+            .round()
+            .clamp(0.0, MAX_GAIN * 10.0)
+            / 10.0;
+        sink.gain(self.gain);
+        info!("New synth gain {}", self.gain);
+        beep(sink, 53, 60);
     }
 }
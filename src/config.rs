@@ -0,0 +1,581 @@
+// Runtime settings.  Everything can be set from a TOML file, and any field
+// left out of the file keeps its default value.  Command line flags are applied
+// on top by the binary.
+//
+//   [synth]
+//   sf2_file = "/usr/share/sounds/sf2/TimGM6mb.sf2"
+//   prog_number = 66
+//
+//   [notemap]
+//   transpose = -9
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::curve::Curve;
+use crate::fallback::{MaskRule, Strategy};
+use crate::filter::Filter;
+use crate::keys::Keys;
+
+pub const DEFAULT_CONFIG_FILE: &str = "/etc/haxo/haxo.toml";
+
+// Readings taken on every tick have to fit in it
+const MAX_OVERSAMPLING: u32 = 8;
+// Widest span of the 12-bit pressure sensor.  The response curve keeps a
+// table entry per count up to the ceiling.
+const MAX_CEILING: i32 = 4095;
+
+/// Highest synth gain fluidsynth accepts.
+pub const MAX_GAIN: f32 = 10.0;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub synth: SynthConfig,
+    pub notemap: NotemapConfig,
+    pub timing: TimingConfig,
+    pub pressure: PressureConfig,
+    pub velocity: VelocityConfig,
+    pub thresholds: ThresholdsConfig,
+    pub state: StateConfig,
+    pub matrix: MatrixConfig,
+    pub diagnostics: DiagnosticsConfig,
+    pub fallback: FallbackConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SynthConfig {
+    pub sf2_file: String,
+    pub prog_number: i32,
+    pub gain: f32,
+    // Number of voices while playing.  The startup tune always gets 16.
+    pub polyphony: i32,
+    // Audio buffer size, in frames, and number of buffers.  Smaller values
+    // reduce latency but may cause underruns.
+    pub period_size: i32,
+    pub periods: i32,
+}
+
+impl Default for SynthConfig {
+    fn default() -> Self {
+        SynthConfig {
+            sf2_file: String::from("/usr/share/sounds/sf2/FluidR3_GM.sf2"),
+            prog_number: 67,
+            gain: 1.0,
+            polyphony: 1,
+            period_size: 64,
+            periods: 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotemapConfig {
+    // Notemap to play with when no profile is selected
+    pub file: String,
+    pub transpose: i32,
+    // Directory of named notemaps, to switch between in control mode
+    pub profiles_dir: String,
+    // Profile to play with instead of `file`, if not empty
+    pub profile: String,
+}
+
+impl Default for NotemapConfig {
+    fn default() -> Self {
+        NotemapConfig {
+            file: String::from("./notemap.json"),
+            transpose: -14,
+            profiles_dir: String::from("./profiles"),
+            profile: String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingConfig {
+    // Period of the key and pressure scan loop
+    pub tick_usecs: u32,
+    // How long to draw air before entering control or transpose mode
+    pub neg_press_countdown_ms: u32,
+    // How long a transpose selection must be held before it is applied
+    pub transpose_countdown_ms: u32,
+    // Length of each step of the breath calibration
+    pub calibration_window_ms: u32,
+    // How long a key must hold a new state before it is believed.  A few
+    // milliseconds filter out contact bounce.
+    pub debounce_ms: u32,
+    // How long a new fingering must be held before it changes a sounding
+    // note.  Filters out the passing fingerings of keys that do not move
+    // exactly together.
+    pub settle_ms: u32,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        TimingConfig {
+            tick_usecs: 2_000,
+            neg_press_countdown_ms: 500,
+            transpose_countdown_ms: 200,
+            calibration_window_ms: 3_000,
+            debounce_ms: 0,
+            settle_ms: 0,
+        }
+    }
+}
+
+impl TimingConfig {
+    /// Convert a duration in milliseconds to a number of scan ticks.  Very
+    /// long durations saturate rather than wrap around.
+    pub fn ms_to_ticks(&self, ms: u32) -> u32 {
+        let ticks = u64::from(ms) * 1000 / u64::from(self.tick_usecs);
+        u32::try_from(ticks).unwrap_or(u32::MAX)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PressureConfig {
+    // Raw sensor reading with no breath.  Measured at startup when not set.
+    pub baseline: Option<i32>,
+    // Raw sensor counts above the baseline that still give no sound
+    pub dead_zone: i32,
+    // Raw sensor counts above the baseline that give full volume
+    pub ceiling: i32,
+    // Follow slow changes of the zero pressure reading while no note sounds
+    pub track_drift: bool,
+    // Largest spread of raw readings that still counts as stable, and the
+    // farthest from the baseline they are followed.  Anything further is a
+    // deliberate breath, even without a note sounding.
+    pub drift_band: i32,
+    // Log a warning when the baseline moves this many counts from startup
+    pub drift_warning: i32,
+    // Sensor readings averaged on every tick.  Each one takes a fraction of a
+    // millisecond on the I2C bus.
+    pub oversampling: u32,
+    // Must come last, tables cannot be followed by plain values in TOML
+    pub curve: Curve,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<Filter>,
+}
+
+impl Default for PressureConfig {
+    fn default() -> Self {
+        PressureConfig {
+            baseline: None,
+            dead_zone: 0,
+            ceiling: 762,
+            track_drift: true,
+            drift_band: 8,
+            drift_warning: 50,
+            oversampling: 1,
+            curve: Curve::Linear,
+            filters: Vec::new(),
+        }
+    }
+}
+
+impl PressureConfig {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(baseline) = self.baseline {
+            if !(-2048..=2047).contains(&baseline) {
+                return Err("pressure.baseline must be between -2048 and 2047".into());
+            }
+        }
+        if self.dead_zone < 0 {
+            return Err("pressure.dead_zone must not be negative".into());
+        }
+        if self.ceiling <= self.dead_zone {
+            return Err("pressure.ceiling must be greater than pressure.dead_zone".into());
+        }
+        if self.ceiling > MAX_CEILING {
+            return Err(format!("pressure.ceiling must not be above {}", MAX_CEILING).into());
+        }
+        if !(1..=MAX_OVERSAMPLING).contains(&self.oversampling) {
+            return Err(format!(
+                "pressure.oversampling must be between 1 and {}",
+                MAX_OVERSAMPLING
+            )
+            .into());
+        }
+        self.curve.validate()?;
+        for filter in self.filters.iter() {
+            filter.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VelocityConfig {
+    // Take the note-on velocity from how fast the breath rises at the start
+    // of a note.  Otherwise notes always start at full velocity.
+    pub from_breath: bool,
+    // How long the breath rise is measured for.  Delays the start of notes by
+    // as much.
+    pub onset_ms: u32,
+    // Breath level reached at the end of the onset that gives full velocity
+    pub full_rise: i32,
+    // Velocity of the softest attacks
+    pub min: i32,
+    // Notes started by a fingering change while blowing take their velocity
+    // from the breath level at that moment
+    pub legato_from_breath: bool,
+}
+
+impl Default for VelocityConfig {
+    fn default() -> Self {
+        VelocityConfig {
+            from_breath: false,
+            onset_ms: 6,
+            full_rise: 40,
+            min: 30,
+            legato_from_breath: false,
+        }
+    }
+}
+
+impl VelocityConfig {
+    /// Velocity for a breath level, between `min` and 127.
+    pub fn scale(&self, level: i32, full: i32) -> i32 {
+        (level * 127 / full).clamp(self.min, 127)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThresholdsConfig {
+    // Breath level at which a note starts
+    pub note_on: i32,
+    // Breath level at or below which a note stops.  Keep it under note_on so
+    // breath noise around a single level does not restart notes.
+    pub note_off: i32,
+    // Shortest time a note sounds, even if the breath stops earlier
+    pub min_note_ms: u32,
+    // Blowing above this level is a deliberate breath, for recording the
+    // notemap and transposing
+    pub blow: i32,
+    // Drawing below this level is deliberate, for mode changes and recording
+    pub draw: i32,
+}
+
+impl Default for ThresholdsConfig {
+    fn default() -> Self {
+        ThresholdsConfig {
+            note_on: 1,
+            note_off: 0,
+            min_note_ms: 0,
+            blow: 10,
+            draw: -10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    // Where to keep settings changed while playing.  Must be writable, so
+    // point it to a USB drive when the root file system is read-only.  Leave
+    // empty to not keep any state.  Changes are not kept either when the file
+    // cannot be written.
+    pub file: String,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        StateConfig {
+            file: String::from("/var/lib/haxo/state.toml"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FallbackConfig {
+    // How to find a note for key combinations missing from the notemap,
+    // tried in order.  With none, the current note keeps sounding.
+    pub strategies: Vec<Strategy>,
+    // Keys the ignore-keys strategy may leave out: keys that are easy to
+    // touch by accident, such as the palm, side and table keys
+    pub ignore_keys: Keys,
+    // Most keys the nearest strategy may add or leave out
+    pub max_distance: u32,
+    // Must come last, tables cannot be followed by plain values in TOML
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub masks: Vec<MaskRule>,
+}
+
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        FallbackConfig {
+            strategies: Vec::new(),
+            ignore_keys: Keys(0),
+            max_distance: 1,
+            masks: Vec::new(),
+        }
+    }
+}
+
+// Pins the HAT uses for other purposes: the pressure sensor, the serial
+// console used for instrumentation and the audio codec
+const RESERVED_PINS: [(u8, &str); 7] = [
+    (2, "I2C SDA"),
+    (3, "I2C SCL"),
+    (14, "UART TX"),
+    (15, "UART RX"),
+    (18, "I2S BCLK"),
+    (19, "I2S LRCLK"),
+    (21, "I2S DOUT"),
+];
+
+// Highest pin on the 40 pin header, BCM numbering
+const MAX_PIN: u8 = 27;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatrixConfig {
+    // Key matrix pins, BCM numbering.  Rows are driven low one at a time and
+    // the columns read.  The key at row r and column c is bit r * cols + c of
+    // the key bitmap.  The control and transpose keys and the shipped
+    // profiles expect the 8 rows and 3 columns of the Haxophone HAT.
+    pub rows: Vec<u8>,
+    pub cols: Vec<u8>,
+    // How long to wait after driving a row low before reading the columns
+    pub row_settle_us: u32,
+    // Enable the internal pull-ups on the column pins.  Turn off for boards
+    // with external pull-up resistors.
+    pub pull_up: bool,
+}
+
+impl Default for MatrixConfig {
+    fn default() -> Self {
+        MatrixConfig {
+            rows: vec![13, 12, 16, 17, 20, 22, 23, 24],
+            cols: vec![25, 26, 27],
+            row_settle_us: 10,
+            pull_up: true,
+        }
+    }
+}
+
+impl MatrixConfig {
+    /// Whether keys land on the same bits as with the Haxophone HAT.
+    pub fn has_default_layout(&self) -> bool {
+        let default = MatrixConfig::default();
+        self.rows.len() == default.rows.len() && self.cols.len() == default.cols.len()
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.rows.is_empty() || self.cols.is_empty() {
+            return Err("matrix: rows and cols must not be empty".into());
+        }
+        if self.rows.len() * self.cols.len() > 32 {
+            return Err("matrix: at most 32 keys fit in the key bitmap".into());
+        }
+        let pins: Vec<u8> = self.rows.iter().chain(self.cols.iter()).copied().collect();
+        for (i, pin) in pins.iter().enumerate() {
+            if *pin > MAX_PIN {
+                return Err(format!("matrix: there is no GPIO {}", pin).into());
+            }
+            if pins[..i].contains(pin) {
+                return Err(format!("matrix: GPIO {} is used twice", pin).into());
+            }
+            if let Some((_, name)) = RESERVED_PINS.iter().find(|(reserved, _)| reserved == pin) {
+                return Err(format!("matrix: GPIO {} is reserved for {}", pin, name).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiagnosticsConfig {
+    // Report keys held down for longer than this.  0 turns the check off.
+    pub stuck_key_ms: u32,
+    // Report key combinations that hint at ghosting or a shorted row or
+    // column once they last this long.  0 turns the check off.
+    pub fault_ms: u32,
+    // Beep on every fault reported, besides logging it
+    pub beep: bool,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        DiagnosticsConfig {
+            stuck_key_ms: 120_000,
+            fault_ms: 500,
+            beep: true,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(configfile: &str) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(configfile)
+            .map_err(|e| format!("Failed to read {}: {}", configfile, e))?;
+        Config::parse(&contents).map_err(|e| format!("{}: {}", configfile, e).into())
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, Box<dyn Error>> {
+        let config: Config = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("Config is always serializable")
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.timing.tick_usecs == 0 {
+            return Err("timing.tick_usecs must be greater than 0".into());
+        }
+        self.pressure.validate()?;
+        self.matrix.validate()?;
+        let synth = &self.synth;
+        if !(0..=127).contains(&synth.prog_number) {
+            return Err("synth.prog_number must be between 0 and 127".into());
+        }
+        // The ranges fluidsynth accepts
+        if !(0.0..=MAX_GAIN).contains(&synth.gain) {
+            return Err(format!("synth.gain must be between 0 and {}", MAX_GAIN).into());
+        }
+        if !(1..=256).contains(&synth.polyphony) {
+            return Err("synth.polyphony must be between 1 and 256".into());
+        }
+        if !(64..=8192).contains(&synth.period_size) {
+            return Err("synth.period_size must be between 64 and 8192".into());
+        }
+        if !(2..=64).contains(&synth.periods) {
+            return Err("synth.periods must be between 2 and 64".into());
+        }
+        let thresholds = &self.thresholds;
+        if thresholds.note_off < 0 || thresholds.note_off >= thresholds.note_on {
+            return Err("thresholds.note_off must be between 0 and thresholds.note_on".into());
+        }
+        if thresholds.blow <= 0 || thresholds.draw >= 0 {
+            return Err("thresholds.blow must be positive and thresholds.draw negative".into());
+        }
+        if self.velocity.full_rise <= 0 {
+            return Err("velocity.full_rise must be greater than 0".into());
+        }
+        if !(1..=127).contains(&self.velocity.min) {
+            return Err("velocity.min must be between 1 and 127".into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_durations() {
+        let timing = TimingConfig::default();
+        // Two hours
+        assert_eq!(timing.ms_to_ticks(7_200_000), 3_600_000);
+        let timing = TimingConfig {
+            tick_usecs: 1,
+            ..TimingConfig::default()
+        };
+        assert_eq!(timing.ms_to_ticks(7_200_000), u32::MAX);
+    }
+
+    #[test]
+    fn partial() -> Result<(), Box<dyn Error>> {
+        let config = Config::parse(
+            "[synth]
+            prog_number = 66
+
+            [timing]
+            tick_usecs = 1000",
+        )?;
+        assert_eq!(config.synth.prog_number, 66);
+        assert_eq!(config.synth.sf2_file, SynthConfig::default().sf2_file);
+        assert_eq!(config.timing.tick_usecs, 1000);
+        assert_eq!(config.timing.ms_to_ticks(500), 500);
+        assert_eq!(config.notemap, NotemapConfig::default());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() -> Result<(), Box<dyn Error>> {
+        let config = Config::default();
+        assert_eq!(Config::parse(&config.to_toml())?, config);
+        Ok(())
+    }
+
+    #[test]
+    fn filters() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        config.pressure.filters =
+            vec![Filter::Median { window: 5 }, Filter::Hysteresis { band: 4 }];
+        config.pressure.oversampling = 4;
+        assert_eq!(Config::parse(&config.to_toml())?, config);
+        assert!(Config::parse("[pressure]\noversampling = 0").is_err());
+        assert!(Config::parse("[pressure]\noversampling = 9").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fallback() -> Result<(), Box<dyn Error>> {
+        let config = Config::parse(
+            "[fallback]
+            strategies = [\"ignore-keys\", \"nearest\"]
+            ignore_keys = [\"palm-d\", \"side-c\"]
+
+            [[fallback.masks]]
+            when = [\"g#\"]
+            ignore = [\"low-c#\"]",
+        )?;
+        assert_eq!(
+            config.fallback.strategies,
+            vec![Strategy::IgnoreKeys, Strategy::Nearest]
+        );
+        assert_eq!(config.fallback.ignore_keys, Keys(0x60));
+        assert_eq!(config.fallback.masks[0].ignore, Keys(0x20000));
+        assert_eq!(Config::parse(&config.to_toml())?, config);
+        assert!(Config::parse("[fallback]\nignore_keys = [\"palm-x\"]").is_err());
+        assert!(Config::parse("[fallback]\nstrategies = [\"closest\"]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Config::parse("[synth]\nprog_numbr = 66").is_err());
+        assert!(Config::parse("[synth]\nprog_number = \"66\"").is_err());
+        assert!(Config::parse("[synth]\ngain = -0.5").is_err());
+        assert!(Config::parse("[synth]\npolyphony = 0").is_err());
+        assert!(Config::parse("[synth]\nperiod_size = 16").is_err());
+        assert!(Config::parse("[timing]\ntick_usecs = 0").is_err());
+        assert!(Config::parse("[pressure]\nceiling = 0").is_err());
+        assert!(Config::parse("[pressure]\nceiling = 4096").is_err());
+        assert!(Config::parse("[thresholds]\nnote_on = 5\nnote_off = 5").is_err());
+    }
+
+    #[test]
+    fn matrix() -> Result<(), Box<dyn Error>> {
+        let config = Config::parse("[matrix]\nrows = [4, 5]\ncols = [6, 7, 8, 9]")?;
+        assert_eq!(config.matrix.rows, vec![4, 5]);
+        assert_eq!(config.matrix.row_settle_us, 10);
+        assert!(!config.matrix.has_default_layout());
+        // Other pins, same shape
+        let moved = "[matrix]\nrows = [4, 5, 6, 7, 8, 9, 10, 11]\ncols = [13, 16, 17]";
+        assert!(Config::parse(moved)?.matrix.has_default_layout());
+        assert!(Config::parse("[matrix]\nrows = []").is_err());
+        assert!(Config::parse("[matrix]\nrows = [4, 5]\ncols = [6, 4]").is_err());
+        assert!(Config::parse("[matrix]\nrows = [4, 14]").is_err());
+        assert!(Config::parse("[matrix]\nrows = [4, 28]").is_err());
+        let wide = "[matrix]\nrows = [4, 5, 6, 7, 8, 9, 10, 11, 12]\ncols = [13, 16, 17, 20]";
+        assert!(Config::parse(wide).is_err());
+        Ok(())
+    }
+}
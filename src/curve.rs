@@ -0,0 +1,192 @@
+// Breath response curves.  Raw pressure counts above the baseline are mapped to
+// the 0-127 MIDI range: readings up to the dead zone give 0, readings at or
+// above the ceiling give 127, and the curve decides what happens in between.
+//
+//   [pressure]
+//   dead_zone = 20
+//   ceiling = 600
+//
+//   [pressure.curve]
+//   type = "exponential"
+//   factor = 3.0
+
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+const MIDI_MAX: i32 = 127;
+// Counts per step of negative pressure.  Fixed so that calibrating the blow
+// range does not change how hard to draw for a mode change.
+const DRAW_SCALE: i32 = 6;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Curve {
+    #[default]
+    Linear,
+    // Soft start, most of the range is at the top.  Larger factors bend more.
+    Exponential {
+        factor: f64,
+    },
+    // Quick start, most of the range is at the bottom
+    Logarithmic {
+        factor: f64,
+    },
+    // Soft at both ends, steep in the middle
+    SCurve {
+        steepness: f64,
+    },
+    // Output values, 0-127, at evenly spaced inputs from the dead zone to the
+    // ceiling.  Values in between are interpolated.
+    Table {
+        points: Vec<i32>,
+    },
+}
+
+impl Curve {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Curve::Linear => {}
+            Curve::Exponential { factor } | Curve::Logarithmic { factor } => {
+                if *factor <= 0.0 {
+                    return Err("pressure.curve.factor must be greater than 0".into());
+                }
+            }
+            Curve::SCurve { steepness } => {
+                if *steepness <= 0.0 {
+                    return Err("pressure.curve.steepness must be greater than 0".into());
+                }
+            }
+            Curve::Table { points } => {
+                if points.len() < 2 {
+                    return Err("pressure.curve.points needs at least 2 values".into());
+                }
+                if points.iter().any(|p| !(0..=MIDI_MAX).contains(p)) {
+                    return Err("pressure.curve.points must be between 0 and 127".into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Map x in [0, 1] to [0, 1]
+    fn shape(&self, x: f64) -> f64 {
+        match self {
+            Curve::Linear => x,
+            Curve::Exponential { factor } => (factor * x).exp_m1() / factor.exp_m1(),
+            Curve::Logarithmic { factor } => (factor * x).ln_1p() / factor.ln_1p(),
+            Curve::SCurve { steepness } => {
+                let logistic = |x: f64| 1.0 / (1.0 + (-steepness * (x - 0.5)).exp());
+                (logistic(x) - logistic(0.0)) / (logistic(1.0) - logistic(0.0))
+            }
+            Curve::Table { points } => {
+                let pos = x * (points.len() - 1) as f64;
+                let i = (pos.floor() as usize).min(points.len() - 2);
+                let frac = pos - i as f64;
+                let (a, b) = (points[i] as f64, points[i + 1] as f64);
+                (a + (b - a) * frac) / MIDI_MAX as f64
+            }
+        }
+    }
+}
+
+/// Precomputed mapping from raw pressure counts to MIDI values.
+pub struct Response {
+    dead_zone: i32,
+    ceiling: i32,
+    table: Vec<i32>,
+}
+
+impl Response {
+    pub fn new(curve: &Curve, dead_zone: i32, ceiling: i32) -> Self {
+        let span = ceiling - dead_zone;
+        let table = (0..=span)
+            .map(|step| match curve {
+                // Integer math so the default curve matches the old fixed
+                // scaling factor exactly
+                Curve::Linear => step * MIDI_MAX / span,
+                _ => (curve.shape(step as f64 / span as f64) * MIDI_MAX as f64).round() as i32,
+            })
+            .collect();
+        Response {
+            dead_zone,
+            ceiling,
+            table,
+        }
+    }
+
+    /// Map a raw reading, relative to the baseline, to 0-127.  Negative
+    /// readings (drawing air) are scaled linearly and kept negative, so they
+    /// can still be used for mode changes.
+    pub fn map(&self, raw: i32) -> i32 {
+        if raw < 0 {
+            return raw / DRAW_SCALE;
+        }
+        let step = (raw - self.dead_zone).clamp(0, self.ceiling - self.dead_zone);
+        self.table[step as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draw_ignores_ceiling() {
+        let response = Response::new(&Curve::Linear, 10, 400);
+        assert_eq!(response.map(-60), -10);
+        assert_eq!(response.map(400), 127);
+    }
+
+    #[test]
+    fn linear_matches_fixed_scaling() {
+        let response = Response::new(&Curve::Linear, 0, 762);
+        for raw in -1000..2000 {
+            assert_eq!(
+                response.map(raw),
+                std::cmp::min(raw / 6, 127),
+                "raw {}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn shapes() {
+        let curves = [
+            Curve::Exponential { factor: 3.0 },
+            Curve::Logarithmic { factor: 3.0 },
+            Curve::SCurve { steepness: 8.0 },
+            Curve::Table {
+                points: vec![0, 10, 127],
+            },
+        ];
+        for curve in curves.iter() {
+            curve.validate().unwrap();
+            let response = Response::new(curve, 10, 210);
+            assert_eq!(response.map(5), 0);
+            assert_eq!(response.map(10), 0);
+            assert_eq!(response.map(210), 127);
+            assert_eq!(response.map(500), 127);
+            let values: Vec<i32> = (10..=210).map(|raw| response.map(raw)).collect();
+            assert!(values.windows(2).all(|w| w[0] <= w[1]), "{:?}", curve);
+        }
+        // Exponential starts soft, logarithmic starts quick
+        let exp = Response::new(&curves[0], 0, 200);
+        let log = Response::new(&curves[1], 0, 200);
+        assert!(exp.map(100) < 64 && log.map(100) > 64);
+        // Table interpolates between points
+        assert_eq!(Response::new(&curves[3], 0, 200).map(50), 5);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Curve::Exponential { factor: 0.0 }.validate().is_err());
+        assert!(Curve::Table { points: vec![0] }.validate().is_err());
+        assert!(Curve::Table {
+            points: vec![0, 128]
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,370 @@
+// Sanity checks on the key matrix.  The matrix has no diodes, so a shorted
+// or stuck contact does not fail loudly: it just adds keys to every fingering.
+// Three things give it away:
+//
+//   - a key held down for longer than anybody holds a key
+//   - four keys on the corners of a rectangle of rows and columns.  Without
+//     diodes, pressing three of them makes the fourth read pressed as well.
+//   - a whole row or column reading pressed, as a shorted line does
+//
+// Rectangles and full lines also occur in real fingerings, so these two are
+// only suspicious when the keys are not a fingering of the notemap, and only
+// once they have lasted a while.
+
+use std::fmt;
+
+use crate::config::{DiagnosticsConfig, MatrixConfig, TimingConfig};
+use crate::keys::Keys;
+use crate::keyscan::KeyId;
+use crate::sink::SoundSink;
+
+/// Low enough not to be mistaken for a note or a mode change.
+pub const ALARM_NOTE: i32 = 40;
+const ALARM_VOLUME: i32 = 50;
+const ALARM_MS: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    StuckKey(KeyId),
+    // Bitmap of the four keys of the rectangle
+    Ghosting(u32),
+    FullRow(usize),
+    FullColumn(usize),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::StuckKey(key) => write!(f, "{} has been held for too long, is it stuck?", key),
+            Fault::Ghosting(keys) => write!(
+                f,
+                "keys {} read pressed together, possible ghosting or shorted contact",
+                Keys(*keys)
+            ),
+            Fault::FullRow(row) => {
+                write!(f, "every key of row {} reads pressed, is it shorted?", row)
+            }
+            Fault::FullColumn(col) => write!(
+                f,
+                "every key of column {} reads pressed, is it shorted?",
+                col
+            ),
+        }
+    }
+}
+
+/// Watches the key bitmaps for signs of a faulty matrix.  Each fault is
+/// reported once, when it is first detected, and again only after it clears.
+pub struct KeyDiagnostics {
+    rows: usize,
+    cols: usize,
+    // Scans each key has been held for
+    key_ticks: [u32; 32],
+    stuck_key_ticks: u32,
+    // Scans each suspicious combination has lasted for
+    ghost_ticks: u32,
+    row_ticks: Vec<u32>,
+    col_ticks: Vec<u32>,
+    fault_ticks: u32,
+}
+
+impl KeyDiagnostics {
+    pub fn new(matrix: &MatrixConfig, config: &DiagnosticsConfig, timing: &TimingConfig) -> Self {
+        KeyDiagnostics {
+            rows: matrix.rows.len(),
+            cols: matrix.cols.len(),
+            key_ticks: [0; 32],
+            stuck_key_ticks: timing.ms_to_ticks(config.stuck_key_ms),
+            ghost_ticks: 0,
+            row_ticks: vec![0; matrix.rows.len()],
+            col_ticks: vec![0; matrix.cols.len()],
+            fault_ticks: timing.ms_to_ticks(config.fault_ms),
+        }
+    }
+
+    // One bit per column.  A single row may have all 32 columns.
+    fn all_cols(&self) -> u32 {
+        u32::MAX >> (32 - self.cols)
+    }
+
+    // Pressed columns of a row, one bit per column
+    fn row_bits(&self, keys: u32, row: usize) -> u32 {
+        keys.checked_shr((row * self.cols) as u32).unwrap_or(0) & self.all_cols()
+    }
+
+    // The first rectangle of pressed keys, if any
+    fn rectangle(&self, keys: u32) -> Option<u32> {
+        for a in 0..self.rows {
+            for b in a + 1..self.rows {
+                let common = self.row_bits(keys, a) & self.row_bits(keys, b);
+                if common.count_ones() >= 2 {
+                    // The two lowest columns in common
+                    let first = common & common.wrapping_neg();
+                    let rest = common & !first;
+                    let cols = first | (rest & rest.wrapping_neg());
+                    return Some((cols << (a * self.cols)) | (cols << (b * self.cols)));
+                }
+            }
+        }
+        None
+    }
+
+    /// Check the keys of one scan.  `fingering` tells whether they play a
+    /// note, fallbacks included.  Returns the faults detected at this scan.
+    pub fn check(&mut self, keys: u32, fingering: bool) -> Vec<Fault> {
+        let mut faults = Vec::new();
+
+        if self.stuck_key_ticks > 0 {
+            for (bit, ticks) in self.key_ticks.iter_mut().enumerate() {
+                if keys & (1 << bit) == 0 {
+                    *ticks = 0;
+                    continue;
+                }
+                *ticks = ticks.saturating_add(1);
+                if *ticks == self.stuck_key_ticks {
+                    faults.push(Fault::StuckKey(KeyId(bit as u8)));
+                }
+            }
+        }
+
+        if self.fault_ticks == 0 {
+            return faults;
+        }
+        let suspicious = !fingering;
+        let rectangle = if suspicious {
+            self.rectangle(keys)
+        } else {
+            None
+        };
+        if count(&mut self.ghost_ticks, rectangle.is_some(), self.fault_ticks) {
+            faults.extend(rectangle.map(Fault::Ghosting));
+        }
+        let all_cols = self.all_cols();
+        for row in 0..self.rows {
+            let full = suspicious && self.row_bits(keys, row) == all_cols;
+            if count(&mut self.row_ticks[row], full, self.fault_ticks) {
+                faults.push(Fault::FullRow(row));
+            }
+        }
+        for col in 0..self.cols {
+            let full =
+                suspicious && (0..self.rows).all(|row| self.row_bits(keys, row) & (1 << col) != 0);
+            if count(&mut self.col_ticks[col], full, self.fault_ticks) {
+                faults.push(Fault::FullColumn(col));
+            }
+        }
+        faults
+    }
+}
+
+/// Beeps about faults without getting in the way of playing.  The beep waits
+/// until no note sounds, stops as soon as one starts, and is timed in scan
+/// ticks rather than by sleeping in the scan loop.
+pub struct FaultAlarm {
+    pending: bool,
+    // Ticks left of the beep sounding, if any
+    remaining: u32,
+    ticks: u32,
+}
+
+impl FaultAlarm {
+    pub fn new(timing: &TimingConfig) -> Self {
+        FaultAlarm {
+            pending: false,
+            remaining: 0,
+            ticks: timing.ms_to_ticks(ALARM_MS).max(1),
+        }
+    }
+
+    /// Beep as soon as nothing is playing.
+    pub fn raise(&mut self) {
+        self.pending = true;
+    }
+
+    /// Called every tick, after the instrument has played, with the note it
+    /// sounds, if any, and the breath level it last sent.
+    pub fn tick(&mut self, note: Option<i32>, volume: i32, sink: &mut dyn SoundSink) {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            if self.remaining == 0 || note.is_some() {
+                self.remaining = 0;
+                sink.feedback(true);
+                if note != Some(ALARM_NOTE) {
+                    sink.noteoff(ALARM_NOTE);
+                }
+                sink.feedback(false);
+                // Back to the instrument's volume
+                sink.breath(volume);
+            }
+            return;
+        }
+        if self.pending && note.is_none() {
+            self.pending = false;
+            self.remaining = self.ticks;
+            sink.feedback(true);
+            sink.noteon(ALARM_NOTE, ALARM_VOLUME);
+            sink.breath(ALARM_VOLUME);
+            sink.feedback(false);
+        }
+    }
+}
+
+// Count the scans a condition has lasted for.  True at the scan it reaches
+// the limit.
+fn count(ticks: &mut u32, condition: bool, limit: u32) -> bool {
+    if !condition {
+        *ticks = 0;
+        return false;
+    }
+    *ticks = ticks.saturating_add(1);
+    *ticks == limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One scan per millisecond
+    fn diagnostics(stuck_key_ms: u32, fault_ms: u32) -> KeyDiagnostics {
+        let config = DiagnosticsConfig {
+            stuck_key_ms,
+            fault_ms,
+            ..DiagnosticsConfig::default()
+        };
+        let timing = TimingConfig {
+            tick_usecs: 1000,
+            ..TimingConfig::default()
+        };
+        KeyDiagnostics::new(&MatrixConfig::default(), &config, &timing)
+    }
+
+    // Faults reported over a number of scans of the same keys
+    fn run(diag: &mut KeyDiagnostics, keys: u32, fingering: bool, scans: u32) -> Vec<Fault> {
+        (0..scans)
+            .flat_map(|_| diag.check(keys, fingering))
+            .collect()
+    }
+
+    #[test]
+    fn stuck_key() {
+        let mut diag = diagnostics(100, 0);
+        assert_eq!(run(&mut diag, 0x80, true, 99), vec![]);
+        assert_eq!(
+            run(&mut diag, 0x480, true, 1),
+            vec![Fault::StuckKey(KeyId(7))]
+        );
+        // Reported once, and again after a release
+        assert_eq!(
+            run(&mut diag, 0x480, true, 1000),
+            vec![Fault::StuckKey(KeyId(10))]
+        );
+        diag.check(0x400, true);
+        assert_eq!(
+            run(&mut diag, 0x480, true, 100),
+            vec![Fault::StuckKey(KeyId(7))]
+        );
+    }
+
+    #[test]
+    fn ghosting() {
+        let mut diag = diagnostics(0, 10);
+        // High E: octave, palm Eb, side E and palm D form a rectangle
+        assert_eq!(run(&mut diag, 0x2d, true, 100), vec![]);
+        assert_eq!(run(&mut diag, 0x2d, false, 9), vec![]);
+        assert_eq!(
+            run(&mut diag, 0x12d, false, 100),
+            vec![Fault::Ghosting(0x2d)]
+        );
+        assert_eq!(
+            Fault::Ghosting(0x2d).to_string(),
+            "keys octave+palm-eb+side-e+palm-d read pressed together, \
+             possible ghosting or shorted contact"
+        );
+    }
+
+    #[test]
+    fn one_row() {
+        let matrix = MatrixConfig {
+            rows: vec![0],
+            cols: (1..=32).collect(),
+            ..MatrixConfig::default()
+        };
+        let timing = TimingConfig {
+            tick_usecs: 1000,
+            ..TimingConfig::default()
+        };
+        let config = DiagnosticsConfig {
+            stuck_key_ms: 0,
+            fault_ms: 1,
+            ..DiagnosticsConfig::default()
+        };
+        let mut diag = KeyDiagnostics::new(&matrix, &config, &timing);
+        // With a single row, every column is full as well
+        let faults = diag.check(u32::MAX, false);
+        assert_eq!(faults[0], Fault::FullRow(0));
+        assert_eq!(faults.len(), 33);
+    }
+
+    #[test]
+    fn alarm() {
+        use crate::sink::{Event, RecordingSink};
+
+        let timing = TimingConfig {
+            tick_usecs: 20_000,
+            ..TimingConfig::default()
+        };
+        let mut alarm = FaultAlarm::new(&timing);
+        let mut sink = RecordingSink::new();
+        alarm.raise();
+        // Waits for the note to stop
+        alarm.tick(Some(60), 30, &mut sink);
+        assert_eq!(sink.events, vec![]);
+        alarm.tick(None, 0, &mut sink);
+        for _ in 0..5 {
+            alarm.tick(None, 0, &mut sink);
+        }
+        assert_eq!(
+            sink.events,
+            vec![
+                Event::Feedback(true),
+                Event::NoteOn { note: 40, vel: 50 },
+                Event::Breath(50),
+                Event::Feedback(false),
+                Event::Feedback(true),
+                Event::NoteOff { note: 40 },
+                Event::Feedback(false),
+                Event::Breath(0),
+            ]
+        );
+
+        // Cut short by a note
+        let mut sink = RecordingSink::new();
+        alarm.raise();
+        alarm.tick(None, 0, &mut sink);
+        alarm.tick(Some(60), 30, &mut sink);
+        alarm.tick(None, 0, &mut sink);
+        assert_eq!(
+            sink.events[4..],
+            [
+                Event::Feedback(true),
+                Event::NoteOff { note: 40 },
+                Event::Feedback(false),
+                Event::Breath(30)
+            ]
+        );
+        assert_eq!(sink.events.len(), 8);
+    }
+
+    #[test]
+    fn full_lines() {
+        let mut diag = diagnostics(0, 10);
+        // Low B is a full row, but a real fingering
+        assert_eq!(run(&mut diag, 0x5d2480, true, 100), vec![]);
+        assert_eq!(run(&mut diag, 0x1c0000, false, 10), vec![Fault::FullRow(6)]);
+        let column = (0..8).fold(0, |keys, row| keys | 1 << (row * 3 + 1));
+        assert_eq!(
+            run(&mut diag, column, false, 10),
+            vec![Fault::FullColumn(1)]
+        );
+    }
+}
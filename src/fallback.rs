@@ -0,0 +1,238 @@
+// What to play for key combinations missing from the notemap.  Without any
+// strategy the current note keeps sounding, as if the keys had not moved.
+// Strategies are tried in order until one finds a fingering of the notemap:
+//
+//   [fallback]
+//   strategies = ["mask", "ignore-keys", "nearest"]
+//   ignore_keys = ["palm-d", "palm-eb", "palm-f", "side-c", "side-bb"]
+//   max_distance = 1
+//
+//   [[fallback.masks]]
+//   when = ["g#"]
+//   ignore = ["low-c#", "low-b", "low-bb"]
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::FallbackConfig;
+use crate::keys::Keys;
+use crate::notemap::NoteMap;
+
+// Beyond this many ignorable keys pressed at once, only ignoring all of them
+// is tried
+const MAX_IGNORED_SUBSETS: u32 = 8;
+// Key combinations whose resolution is remembered.  A player only uses a few,
+// so running out means something odd, e.g. a faulty matrix.
+const MAX_CACHED: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    // Leave out pressed keys of `ignore_keys`, as few as possible
+    IgnoreKeys,
+    // The fingering that differs by the fewest keys, up to `max_distance`.
+    // Ties are ambiguous and resolve to nothing.
+    Nearest,
+    // Leave out the keys of the first matching rule of `masks`
+    Mask,
+}
+
+/// While all the `when` keys are pressed, the `ignore` keys do not count.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaskRule {
+    pub when: Keys,
+    pub ignore: Keys,
+}
+
+pub struct Resolver {
+    config: FallbackConfig,
+    // Resolutions by key bitmap, for as long as the notemap stays the same
+    cache: HashMap<u32, Option<u32>>,
+}
+
+impl Resolver {
+    pub fn new(config: &FallbackConfig) -> Self {
+        Resolver {
+            config: config.clone(),
+            cache: HashMap::new(),
+        }
+    }
+
+    /// The fingering of the notemap to play for keys that are not in it, if
+    /// any strategy finds one.
+    pub fn resolve(&mut self, keys: u32, notemap: &NoteMap) -> Option<u32> {
+        if self.config.strategies.is_empty() {
+            return None;
+        }
+        if let Some(resolved) = self.cache.get(&keys) {
+            return *resolved;
+        }
+        if self.cache.len() >= MAX_CACHED {
+            self.cache.clear();
+        }
+        let resolved = self
+            .config
+            .strategies
+            .iter()
+            .find_map(|strategy| match strategy {
+                Strategy::IgnoreKeys => self.ignore_keys(keys, notemap),
+                Strategy::Nearest => self.nearest(keys, notemap),
+                Strategy::Mask => self.mask(keys, notemap),
+            });
+        self.cache.insert(keys, resolved);
+        resolved
+    }
+
+    /// Forget the resolutions, once the notemap has changed.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+
+    fn ignore_keys(&self, keys: u32, notemap: &NoteMap) -> Option<u32> {
+        let pressed = keys & self.config.ignore_keys.0;
+        if pressed == 0 {
+            return None;
+        }
+        if pressed.count_ones() > MAX_IGNORED_SUBSETS {
+            return Some(keys & !pressed).filter(|keys| notemap.get(keys).is_some());
+        }
+        // Every subset of the pressed ignorable keys, smallest first
+        let mut subsets: Vec<u32> = Vec::new();
+        let mut subset = pressed;
+        while subset != 0 {
+            subsets.push(subset);
+            subset = (subset - 1) & pressed;
+        }
+        subsets.sort_by_key(|subset| (subset.count_ones(), *subset));
+        subsets
+            .into_iter()
+            .map(|subset| keys & !subset)
+            .find(|keys| notemap.get(keys).is_some())
+    }
+
+    fn nearest(&self, keys: u32, notemap: &NoteMap) -> Option<u32> {
+        let mut nearest = None;
+        let mut nearest_distance = self.config.max_distance.saturating_add(1);
+        let mut tied = false;
+        for fingering in notemap.fingerings() {
+            let distance = (fingering ^ keys).count_ones();
+            if distance < nearest_distance {
+                nearest = Some(fingering);
+                nearest_distance = distance;
+                tied = false;
+            } else if distance == nearest_distance {
+                tied = true;
+            }
+        }
+        nearest.filter(|_| !tied)
+    }
+
+    fn mask(&self, keys: u32, notemap: &NoteMap) -> Option<u32> {
+        self.config
+            .masks
+            .iter()
+            .filter(|rule| keys & rule.when.0 == rule.when.0)
+            .map(|rule| keys & !rule.ignore.0)
+            .find(|keys| notemap.get(keys).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use crate::keys::{mask, Key};
+
+    const LOW_G: u32 = mask(&[Key::B, Key::A, Key::G]);
+    const LOW_GSHARP: u32 = mask(&[Key::B, Key::A, Key::G, Key::GSharp]);
+    const LOW_A: u32 = mask(&[Key::B, Key::A]);
+    const MID_B: u32 = Key::B.mask();
+
+    fn notemap() -> NoteMap {
+        let notemap: BTreeMap<u32, i32> = [(LOW_G, 67), (LOW_GSHARP, 68), (LOW_A, 69), (MID_B, 71)]
+            .iter()
+            .copied()
+            .collect();
+        NoteMap::from_map(notemap, 0)
+    }
+
+    fn resolver(strategies: &[Strategy]) -> Resolver {
+        Resolver::new(&FallbackConfig {
+            strategies: strategies.to_vec(),
+            ignore_keys: Keys(mask(&[Key::PalmD, Key::SideC, Key::LowC])),
+            max_distance: 1,
+            masks: vec![MaskRule {
+                when: Keys(Key::GSharp.mask()),
+                ignore: Keys(Key::LowCSharp.mask()),
+            }],
+        })
+    }
+
+    #[test]
+    fn no_strategy() {
+        let notemap = notemap();
+        assert_eq!(
+            resolver(&[]).resolve(LOW_A | Key::SideC.mask(), &notemap),
+            None
+        );
+    }
+
+    #[test]
+    fn ignore_keys() {
+        let notemap = notemap();
+        let mut resolver = resolver(&[Strategy::IgnoreKeys]);
+        let sloppy = LOW_A | Key::SideC.mask() | Key::PalmD.mask();
+        assert_eq!(resolver.resolve(sloppy, &notemap), Some(LOW_A));
+        assert_eq!(resolver.resolve(LOW_A | Key::F.mask(), &notemap), None);
+    }
+
+    #[test]
+    fn nearest() {
+        let notemap = notemap();
+        let mut resolver = resolver(&[Strategy::Nearest]);
+        assert_eq!(
+            resolver.resolve(LOW_G | Key::F.mask(), &notemap),
+            Some(LOW_G)
+        );
+        // Two keys away from anything
+        assert_eq!(
+            resolver.resolve(Key::F.mask() | Key::E.mask(), &notemap),
+            None
+        );
+        assert_eq!(resolver.resolve(Key::A.mask(), &notemap), Some(LOW_A));
+        // As close to Low G as to Mid B
+        assert_eq!(
+            resolver.resolve(Key::B.mask() | Key::G.mask(), &notemap),
+            None
+        );
+    }
+
+    #[test]
+    fn masks() {
+        let notemap = notemap();
+        let mut resolver = resolver(&[Strategy::Mask]);
+        let keys = LOW_GSHARP | Key::LowCSharp.mask();
+        assert_eq!(resolver.resolve(keys, &notemap), Some(LOW_GSHARP));
+        // Low C# only counts for nothing together with G#
+        assert_eq!(
+            resolver.resolve(LOW_G | Key::LowCSharp.mask(), &notemap),
+            None
+        );
+    }
+
+    #[test]
+    fn cache() {
+        let mut resolver = resolver(&[Strategy::Nearest]);
+        let keys = Key::A.mask();
+        assert_eq!(resolver.resolve(keys, &notemap()), Some(LOW_A));
+        // Until cleared, the resolution stays even if the notemap changes
+        let empty = NoteMap::from_map(BTreeMap::new(), 0);
+        assert_eq!(resolver.resolve(keys, &empty), Some(LOW_A));
+        resolver.clear();
+        assert_eq!(resolver.resolve(keys, &empty), None);
+    }
+}
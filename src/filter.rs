@@ -0,0 +1,282 @@
+// Breath reading filters.  Sensor jitter of a single step changes the volume
+// and costs a CC message on almost every tick.  Filters run in the order they
+// are configured, on raw sensor counts above the baseline, before the response
+// curve scales them to 0-127:
+//
+//   [[pressure.filters]]
+//   type = "median"
+//   window = 5
+//
+//   [[pressure.filters]]
+//   type = "hysteresis"
+//   band = 4
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+// Log the filter statistics this often, in reads (2 minutes at the default
+// tick)
+const STATS_INTERVAL: u64 = 60_000;
+
+// Longest filter history.  Every tick goes through all of it, so it must stay
+// small next to the scan period.
+const MAX_WINDOW: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Filter {
+    // Mean of the last readings
+    MovingAverage { window: usize },
+    // One-pole low-pass.  Smaller alpha filters more, 1.0 does nothing.
+    LowPass { alpha: f32 },
+    // Middle value of the last readings, removes isolated spikes
+    Median { window: usize },
+    // Ignore changes of up to `band` counts.  Silence always gets through so
+    // notes can end.
+    Hysteresis { band: i32 },
+}
+
+impl Filter {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Filter::MovingAverage { window } | Filter::Median { window } => {
+                if !(1..=MAX_WINDOW).contains(window) {
+                    return Err(format!(
+                        "pressure.filters: window must be between 1 and {}",
+                        MAX_WINDOW
+                    )
+                    .into());
+                }
+            }
+            Filter::LowPass { alpha } => {
+                if !(*alpha > 0.0 && *alpha <= 1.0) {
+                    return Err("pressure.filters: alpha must be in (0, 1]".into());
+                }
+            }
+            Filter::Hysteresis { band } => {
+                if *band < 0 {
+                    return Err("pressure.filters: band must not be negative".into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A filter and its history
+struct Stage {
+    filter: Filter,
+    history: VecDeque<i32>,
+    value: f32,
+    output: i32,
+}
+
+impl Stage {
+    fn new(filter: &Filter) -> Self {
+        Stage {
+            filter: filter.clone(),
+            history: VecDeque::new(),
+            value: 0.0,
+            output: 0,
+        }
+    }
+
+    fn push(&mut self, input: i32, window: usize) {
+        self.history.push_back(input);
+        if self.history.len() > window {
+            self.history.pop_front();
+        }
+    }
+
+    fn process(&mut self, input: i32, map: impl Fn(i32) -> i32) -> i32 {
+        self.output = match self.filter {
+            Filter::MovingAverage { window } => {
+                self.push(input, window);
+                let sum: i32 = self.history.iter().sum();
+                (sum as f32 / self.history.len() as f32).round() as i32
+            }
+            Filter::LowPass { alpha } => {
+                self.value += (input as f32 - self.value) * alpha;
+                self.value.round() as i32
+            }
+            Filter::Median { window } => {
+                self.push(input, window);
+                let mut sorted: Vec<i32> = self.history.iter().copied().collect();
+                sorted.sort_unstable();
+                sorted[sorted.len() / 2]
+            }
+            Filter::Hysteresis { band } => {
+                if map(input) <= 0 || (input - self.output).abs() > band {
+                    input
+                } else {
+                    self.output
+                }
+            }
+        };
+        self.output
+    }
+}
+
+/// How many volume changes, each one a CC message, the filters removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FilterStats {
+    pub reads: u64,
+    pub unfiltered_changes: u64,
+    pub filtered_changes: u64,
+}
+
+impl fmt::Display for FilterStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let saved = self
+            .unfiltered_changes
+            .saturating_sub(self.filtered_changes);
+        write!(
+            f,
+            "{} reads, {} of {} breath messages saved ({}%)",
+            self.reads,
+            saved,
+            self.unfiltered_changes,
+            saved * 100 / self.unfiltered_changes.max(1)
+        )
+    }
+}
+
+/// The configured filters, with statistics on what they saved.
+pub struct Filters {
+    stages: Vec<Stage>,
+    last_unfiltered: i32,
+    last_filtered: i32,
+    stats: FilterStats,
+    last_report: u64,
+}
+
+impl Filters {
+    pub fn new(filters: &[Filter]) -> Self {
+        Filters {
+            stages: filters.iter().map(Stage::new).collect(),
+            last_unfiltered: 0,
+            last_filtered: 0,
+            stats: FilterStats::default(),
+            last_report: 0,
+        }
+    }
+
+    pub fn stats(&self) -> FilterStats {
+        self.stats
+    }
+
+    /// Filter a raw reading and scale it with `map`, the response curve.
+    pub fn process(&mut self, raw: i32, map: impl Fn(i32) -> i32) -> i32 {
+        let filtered = self
+            .stages
+            .iter_mut()
+            .fold(raw, |value, stage| stage.process(value, &map));
+        let unfiltered = map(raw);
+        let filtered = map(filtered);
+
+        // Negative values never change the volume
+        let stats = &mut self.stats;
+        stats.reads += 1;
+        if unfiltered.max(0) != self.last_unfiltered.max(0) {
+            stats.unfiltered_changes += 1;
+        }
+        if filtered.max(0) != self.last_filtered.max(0) {
+            stats.filtered_changes += 1;
+        }
+        self.last_unfiltered = unfiltered;
+        self.last_filtered = filtered;
+        if !self.stages.is_empty() && stats.reads - self.last_report >= STATS_INTERVAL {
+            info!("Breath filters: {}", stats);
+            self.last_report = stats.reads;
+        }
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::curve::{Curve, Response};
+
+    fn filter(filters: &[Filter], input: &[i32]) -> Vec<i32> {
+        let mut filters = Filters::new(filters);
+        input
+            .iter()
+            .map(|raw| filters.process(*raw, |value| value))
+            .collect()
+    }
+
+    #[test]
+    fn filters() {
+        let input = [0, 40, 41, 40, 90, 41, 42, 0];
+        assert_eq!(filter(&[], &input), input);
+        assert_eq!(
+            filter(&[Filter::MovingAverage { window: 2 }], &input),
+            vec![0, 20, 41, 41, 65, 66, 42, 21]
+        );
+        assert_eq!(
+            filter(&[Filter::LowPass { alpha: 0.5 }], &input),
+            vec![0, 20, 31, 35, 63, 52, 47, 23]
+        );
+        assert_eq!(
+            filter(&[Filter::Median { window: 3 }], &input),
+            vec![0, 40, 40, 40, 41, 41, 42, 41]
+        );
+        assert_eq!(
+            filter(&[Filter::Hysteresis { band: 1 }], &input),
+            vec![0, 40, 40, 40, 90, 41, 41, 0]
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let input = [0, 40, 41, 40, 41, 40, 0, -20];
+        let mut filters = Filters::new(&[Filter::Hysteresis { band: 1 }]);
+        for raw in input.iter() {
+            filters.process(*raw, |value| value);
+        }
+        assert_eq!(
+            filters.stats(),
+            FilterStats {
+                reads: 8,
+                unfiltered_changes: 6,
+                filtered_changes: 2,
+            }
+        );
+        assert_eq!(
+            filters.stats().to_string(),
+            "8 reads, 4 of 6 breath messages saved (66%)"
+        );
+    }
+
+    #[test]
+    fn raw_counts() {
+        // Jitter of a few counts stays within one volume step once filtered,
+        // while the curve alone turns it into a change on almost every read
+        let response = Response::new(&Curve::Linear, 0, 762);
+        let input = [300, 306, 299, 305, 300, 306, 0];
+        let mut filters = Filters::new(&[Filter::Hysteresis { band: 6 }]);
+        let volumes: Vec<i32> = input
+            .iter()
+            .map(|raw| filters.process(*raw, |value| response.map(value)))
+            .collect();
+        assert_eq!(volumes, vec![50, 50, 50, 50, 50, 50, 0]);
+        assert_eq!(filters.stats().unfiltered_changes, 6);
+        assert_eq!(filters.stats().filtered_changes, 2);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Filter::Median { window: 0 }.validate().is_err());
+        assert!(Filter::Median { window: 64 }.validate().is_ok());
+        assert!(Filter::Median { window: 100_000 }.validate().is_err());
+        assert!(Filter::MovingAverage { window: 65 }.validate().is_err());
+        assert!(Filter::LowPass { alpha: 0.0 }.validate().is_err());
+        assert!(Filter::Hysteresis { band: -1 }.validate().is_err());
+    }
+}
@@ -0,0 +1,679 @@
+use std::cmp::max;
+
+use log::{debug, info, log_enabled, Level};
+
+use crate::commands;
+use crate::config::{Config, MatrixConfig, ThresholdsConfig, VelocityConfig};
+use crate::fallback::Resolver;
+use crate::keys::{mask, Key, Keys};
+use crate::keyscan::{self, KeyScan};
+use crate::notemap::NoteMap;
+use crate::sink::{beep, Event, RecordingSink, SoundSink};
+use crate::state::State;
+use crate::transpose;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Play,
+    Control,
+    Transpose,
+}
+
+/// Requests that the instrument cannot carry out by itself, for the caller to
+/// act on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Calibrate,
+    // Play with the notemap profile this many places away
+    SwitchProfile(i32),
+}
+
+// All three left hand palm keys pressed at once
+const RETURN_TO_PLAY_KEYS: u32 = mask(&[Key::PalmEb, Key::PalmD, Key::PalmF]);
+// Low Bb and Low B of the standard fingering, held while drawing breath.  Fixed
+// so that every profile can get into the modes, the bis key is ignored.
+const CONTROL_KEYS: u32 = mask(&[
+    Key::B,
+    Key::A,
+    Key::G,
+    Key::F,
+    Key::LowC,
+    Key::E,
+    Key::D,
+    Key::LowBb,
+]);
+const TRANSPOSE_KEYS: u32 = mask(&[
+    Key::B,
+    Key::A,
+    Key::G,
+    Key::F,
+    Key::LowC,
+    Key::E,
+    Key::LowB,
+    Key::D,
+]);
+
+/// The play/control/transpose state machine.  It is fed the key and pressure
+/// readings of every scan tick and decides which sound events they produce.
+pub struct Instrument {
+    notemap: NoteMap,
+    mode: Mode,
+    cmd: commands::Command,
+    transpose: transpose::Transpose,
+    last_note: i32,
+    last_vol: i32,
+    neg_pressure_countdown: u32,
+    neg_pressure_init: u32,
+    thresholds: ThresholdsConfig,
+    // Ticks the current note has sounded for, and the least it must
+    note_ticks: u32,
+    min_note_ticks: u32,
+    // Ticks the keys have been held as they are, and how many are needed
+    // before a new fingering changes a sounding note
+    keys_stable: u32,
+    settle_ticks: u32,
+    velocity: VelocityConfig,
+    // Ticks the breath attack is measured for, and how far into it we are
+    onset_ticks: u32,
+    onset: Option<u32>,
+    action: Option<Action>,
+    fallback: Resolver,
+    // Only for printing the key matrix in debug logs
+    matrix: MatrixConfig,
+}
+
+impl Instrument {
+    pub fn new(notemap: NoteMap, config: &Config) -> Self {
+        let timing = &config.timing;
+        // Countdowns must take at least a tick to reach zero
+        let neg_pressure_init = max(1, timing.ms_to_ticks(timing.neg_press_countdown_ms));
+        Instrument {
+            notemap,
+            mode: Mode::Play,
+            cmd: commands::Command::new(config.synth.prog_number, config.synth.gain),
+            transpose: transpose::Transpose::new(max(
+                1,
+                timing.ms_to_ticks(timing.transpose_countdown_ms),
+            )),
+            last_note: 0,
+            last_vol: 0,
+            neg_pressure_countdown: neg_pressure_init,
+            neg_pressure_init,
+            thresholds: config.thresholds.clone(),
+            note_ticks: 0,
+            min_note_ticks: timing.ms_to_ticks(config.thresholds.min_note_ms),
+            keys_stable: 0,
+            settle_ticks: timing.ms_to_ticks(timing.settle_ms),
+            velocity: config.velocity.clone(),
+            onset_ticks: max(1, timing.ms_to_ticks(config.velocity.onset_ms)),
+            onset: None,
+            action: None,
+            fallback: Resolver::new(&config.fallback),
+            matrix: config.matrix.clone(),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Settings the player can change while playing.
+    pub fn state(&self) -> State {
+        State {
+            prog_number: Some(self.cmd.prog_number()),
+            gain: Some(self.cmd.gain()),
+            transpose: Some(self.notemap.transpose),
+            ..State::default()
+        }
+    }
+
+    /// Play with another notemap from now on, at the current transpose.
+    pub fn set_notemap(&mut self, mut notemap: NoteMap) {
+        notemap.transpose = self.notemap.transpose;
+        self.notemap = notemap;
+        self.fallback.clear();
+    }
+
+    /// Whether new fingerings are being recorded into the notemap.
+    pub fn is_recording(&self) -> bool {
+        self.notemap.is_recording()
+    }
+
+    /// Whether the keys play a note, as a fingering of the notemap or through
+    /// a fallback.
+    pub fn is_fingering(&mut self, keys: u32) -> bool {
+        self.notemap.get(&keys).is_some() || self.fallback.resolve(keys, &self.notemap).is_some()
+    }
+
+    // Note of the fallback fingering for keys missing from the notemap
+    fn resolve(&mut self, keys: u32) -> Option<i32> {
+        let fingering = self.fallback.resolve(keys, &self.notemap)?;
+        if self.keys_stable == 0 {
+            debug!("Keys {} play as {}", Keys(keys), Keys(fingering));
+        }
+        self.notemap.get(&fingering)
+    }
+
+    /// Whether a note is currently playing.
+    pub fn is_sounding(&self) -> bool {
+        self.last_note > 0
+    }
+
+    /// The note currently playing, if any.
+    pub fn note(&self) -> Option<i32> {
+        Some(self.last_note).filter(|note| *note > 0)
+    }
+
+    /// Breath level last sent to the sound sinks.
+    pub fn volume(&self) -> i32 {
+        self.last_vol
+    }
+
+    /// Action requested by the player since the last call, if any.
+    pub fn take_action(&mut self) -> Option<Action> {
+        self.action.take()
+    }
+
+    /// Process one scan tick worth of input and return the events to send to
+    /// the sound sinks, in order.
+    pub fn tick(&mut self, scan: &KeyScan, pressure: i32) -> Vec<Event> {
+        let mut out = RecordingSink::new();
+        self.process(scan, pressure, &mut out);
+        out.events
+    }
+
+    fn process(&mut self, scan: &KeyScan, pressure: i32, sink: &mut dyn SoundSink) {
+        let keys = scan.keys;
+        if !scan.events.is_empty() {
+            self.keys_stable = 0;
+        } else {
+            self.keys_stable = self.keys_stable.saturating_add(1);
+        }
+
+        let vol = max(0, pressure);
+        if self.last_vol != vol {
+            sink.breath(vol);
+            self.last_vol = vol;
+        }
+
+        if self.notemap.is_recording() {
+            self.notemap.record(scan, pressure, &self.thresholds);
+            // Recorded fingerings change what the fallbacks find
+            self.fallback.clear();
+        }
+
+        if self.mode == Mode::Control {
+            if let Some(action) = self.cmd.process(scan, sink) {
+                self.action = Some(action);
+            }
+        } else if self.mode == Mode::Transpose {
+            self.transpose
+                .process(keys, vol, &mut self.notemap, &self.thresholds, sink);
+        }
+
+        if self.mode != Mode::Play {
+            if keys == RETURN_TO_PLAY_KEYS {
+                beep(sink, 70, 50);
+                self.mode = Mode::Play;
+                info!("Return to Play Mode");
+            }
+            return;
+        }
+
+        let note = match self.notemap.get(&keys).or_else(|| self.resolve(keys)) {
+            Some(note) => note,
+            None => {
+                if log_enabled!(Level::Debug) {
+                    keyscan::debug_print(keys, &self.matrix);
+                }
+                self.change_mode(keys, pressure, sink);
+                return;
+            }
+        };
+
+        // A sounding note goes on until the breath falls below the note-off
+        // level, and at least for the minimum note duration
+        let blowing = if self.last_note > 0 {
+            self.note_ticks += 1;
+            vol > self.thresholds.note_off || self.note_ticks < self.min_note_ticks
+        } else {
+            vol >= self.thresholds.note_on
+        };
+
+        // Passing fingerings do not interrupt a sounding note
+        let settled = self.last_note == 0 || self.keys_stable >= self.settle_ticks;
+
+        if self.last_note != note && settled {
+            if log_enabled!(Level::Debug) {
+                debug!(
+                    "Note: {} Pressure: {} Keys {:#x}: {}",
+                    self.notemap.get_name(&note).unwrap_or("Unknown?"),
+                    pressure,
+                    keys,
+                    Keys(keys)
+                );
+            };
+            if blowing {
+                let vel = if self.last_note > 0 {
+                    // Dip the volume so the new note gets a fresh attack
+                    sink.breath(0);
+                    sink.noteoff(self.last_note);
+                    sink.breath(vol);
+                    Some(self.legato_velocity(vol))
+                } else {
+                    self.attack_velocity(vol)
+                };
+                if let Some(vel) = vel {
+                    sink.noteon(note, vel);
+                    self.last_note = note;
+                    self.note_ticks = 0;
+                    debug!("last_note changed to {}", self.last_note);
+                }
+            }
+        }
+        if !blowing {
+            self.onset = None;
+        }
+        if !blowing && self.last_note > 0 {
+            sink.noteoff(self.last_note);
+            self.last_note = 0;
+        }
+
+        self.change_mode(keys, pressure, sink);
+    }
+
+    fn change_mode(&mut self, keys: u32, pressure: i32, sink: &mut dyn SoundSink) {
+        // Negative pressure needs to hold for a minimum duration to trigger a mode change
+        if pressure < self.thresholds.draw {
+            self.neg_pressure_countdown = self.neg_pressure_countdown.wrapping_sub(1);
+        } else {
+            self.neg_pressure_countdown = self.neg_pressure_init;
+        }
+        if self.neg_pressure_countdown != 0 {
+            return;
+        }
+
+        match keys & !Key::Bis.mask() {
+            CONTROL_KEYS => {
+                self.mode = Mode::Control;
+                beep(sink, 71, 50);
+                info!("Enter Control Mode");
+            }
+            TRANSPOSE_KEYS => {
+                self.mode = Mode::Transpose;
+                beep(sink, 71, 50);
+                sink.pause(20);
+                beep(sink, 75, 50);
+                info!("Enter Transpose Mode");
+            }
+            _ => {}
+        }
+    }
+
+    // Velocity of a note started from silence, once the breath rise has been
+    // measured for long enough.
+    fn attack_velocity(&mut self, vol: i32) -> Option<i32> {
+        if !self.velocity.from_breath {
+            return Some(127);
+        }
+        let ticks = self.onset.map_or(1, |ticks| ticks + 1);
+        if ticks < self.onset_ticks {
+            self.onset = Some(ticks);
+            return None;
+        }
+        self.onset = None;
+        Some(self.velocity.scale(vol, self.velocity.full_rise))
+    }
+
+    fn legato_velocity(&self, vol: i32) -> i32 {
+        if self.velocity.legato_from_breath {
+            self.velocity.scale(vol, 127)
+        } else {
+            127
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::ops::{Deref, DerefMut};
+    use std::time::Duration;
+
+    use crate::fallback::Strategy;
+    use crate::keyscan::KeyTracker;
+
+    const LOW_BB: u32 = 0xCD2480;
+    const LOW_B: u32 = 0x5D2480;
+    const LOW_A: u32 = 0x480;
+    const MID_B: u32 = 0x80;
+
+    // The instrument, fed key bitmaps through a tracker as the play loop does
+    struct Haxo {
+        instrument: Instrument,
+        tracker: KeyTracker,
+    }
+
+    impl Haxo {
+        fn tick(&mut self, keys: u32, pressure: i32) -> Vec<Event> {
+            let scan = self.tracker.update(keys, Duration::from_millis(0));
+            self.instrument.tick(&scan, pressure)
+        }
+    }
+
+    impl Deref for Haxo {
+        type Target = Instrument;
+
+        fn deref(&self) -> &Instrument {
+            &self.instrument
+        }
+    }
+
+    impl DerefMut for Haxo {
+        fn deref_mut(&mut self) -> &mut Instrument {
+            &mut self.instrument
+        }
+    }
+
+    fn instrument() -> Haxo {
+        instrument_with(&Config::default())
+    }
+
+    fn instrument_with(config: &Config) -> Haxo {
+        let notemap: BTreeMap<u32, i32> = [(LOW_BB, 58), (LOW_B, 59), (LOW_A, 69), (MID_B, 71)]
+            .iter()
+            .copied()
+            .collect();
+        Haxo {
+            instrument: Instrument::new(NoteMap::from_map(notemap, 0), config),
+            tracker: KeyTracker::new(),
+        }
+    }
+
+    fn mode_change_ticks() -> u32 {
+        let timing = Config::default().timing;
+        timing.ms_to_ticks(timing.neg_press_countdown_ms)
+    }
+
+    fn is_note_event(event: &Event) -> bool {
+        matches!(event, Event::NoteOn { .. } | Event::NoteOff { .. })
+    }
+
+    #[test]
+    fn note_on_and_off() {
+        let mut haxo = instrument();
+        assert_eq!(haxo.tick(LOW_A, 0), vec![]);
+        assert_eq!(
+            haxo.tick(LOW_A, 40),
+            vec![Event::Breath(40), Event::NoteOn { note: 69, vel: 127 }]
+        );
+        // Breath only changes are sent as such
+        assert_eq!(haxo.tick(LOW_A, 40), vec![]);
+        assert_eq!(haxo.tick(LOW_A, 50), vec![Event::Breath(50)]);
+        assert_eq!(
+            haxo.tick(LOW_A, 0),
+            vec![Event::Breath(0), Event::NoteOff { note: 69 }]
+        );
+    }
+
+    #[test]
+    fn negative_pressure_is_silent() {
+        let mut haxo = instrument();
+        assert_eq!(haxo.tick(LOW_A, -40), vec![]);
+    }
+
+    #[test]
+    fn legato_dips_volume() {
+        let mut haxo = instrument();
+        haxo.tick(LOW_A, 40);
+        assert_eq!(
+            haxo.tick(MID_B, 40),
+            vec![
+                Event::Breath(0),
+                Event::NoteOff { note: 69 },
+                Event::Breath(40),
+                Event::NoteOn { note: 71, vel: 127 },
+            ]
+        );
+    }
+
+    #[test]
+    fn unmapped_keys_hold_note() {
+        let mut haxo = instrument();
+        haxo.tick(LOW_A, 40);
+        assert_eq!(haxo.tick(0x3, 40), vec![]);
+        assert_eq!(haxo.tick(LOW_A, 40), vec![]);
+    }
+
+    #[test]
+    fn breath_velocity() {
+        let mut config = Config::default();
+        config.velocity.from_breath = true;
+        config.velocity.legato_from_breath = true;
+        let mut haxo = instrument_with(&config);
+        // Onset of 6ms is 3 ticks
+        assert_eq!(haxo.tick(LOW_A, 5), vec![Event::Breath(5)]);
+        assert_eq!(haxo.tick(LOW_A, 10), vec![Event::Breath(10)]);
+        assert_eq!(
+            haxo.tick(LOW_A, 20),
+            vec![Event::Breath(20), Event::NoteOn { note: 69, vel: 63 }]
+        );
+        assert!(haxo
+            .tick(MID_B, 100)
+            .contains(&Event::NoteOn { note: 71, vel: 100 }));
+        haxo.tick(MID_B, 0);
+
+        // Accented attack
+        haxo.tick(LOW_A, 30);
+        haxo.tick(LOW_A, 60);
+        assert!(haxo
+            .tick(LOW_A, 90)
+            .contains(&Event::NoteOn { note: 69, vel: 127 }));
+        haxo.tick(LOW_A, 0);
+
+        // Soft attack, and breath stopping before the end of the onset
+        haxo.tick(LOW_A, 1);
+        haxo.tick(LOW_A, 0);
+        haxo.tick(LOW_A, 1);
+        haxo.tick(LOW_A, 2);
+        assert!(haxo
+            .tick(LOW_A, 3)
+            .contains(&Event::NoteOn { note: 69, vel: 30 }));
+    }
+
+    #[test]
+    fn thresholds() {
+        let mut config = Config::default();
+        config.thresholds.note_on = 10;
+        config.thresholds.note_off = 4;
+        config.thresholds.min_note_ms = 10;
+        let mut haxo = instrument_with(&config);
+        assert!(!haxo.tick(LOW_A, 9).iter().any(is_note_event));
+        assert!(haxo
+            .tick(LOW_A, 10)
+            .contains(&Event::NoteOn { note: 69, vel: 127 }));
+        // Breath noise between the thresholds keeps the note going
+        assert!(!haxo.tick(LOW_A, 6).iter().any(is_note_event));
+        assert!(!haxo.tick(LOW_A, 9).iter().any(is_note_event));
+        // Short notes last the minimum duration of 5 ticks
+        assert!(!haxo.tick(LOW_A, 0).iter().any(is_note_event));
+        assert!(!haxo.tick(LOW_A, 0).iter().any(is_note_event));
+        assert_eq!(haxo.tick(LOW_A, 0), vec![Event::NoteOff { note: 69 }]);
+        assert!(!haxo.tick(LOW_A, 6).iter().any(is_note_event));
+    }
+
+    #[test]
+    fn settle() {
+        let mut config = Config::default();
+        config.timing.settle_ms = 4;
+        let mut haxo = instrument_with(&config);
+        assert!(haxo
+            .tick(LOW_A, 40)
+            .contains(&Event::NoteOn { note: 69, vel: 127 }));
+        // A passing fingering is ignored
+        assert!(!haxo.tick(MID_B, 40).iter().any(is_note_event));
+        assert!(!haxo.tick(LOW_A, 40).iter().any(is_note_event));
+        // A held one takes over after 2 ticks
+        assert!(!haxo.tick(MID_B, 40).iter().any(is_note_event));
+        assert!(!haxo.tick(MID_B, 40).iter().any(is_note_event));
+        assert!(haxo
+            .tick(MID_B, 40)
+            .contains(&Event::NoteOn { note: 71, vel: 127 }));
+    }
+
+    #[test]
+    fn fallback() {
+        let mut config = Config::default();
+        config.fallback.strategies = vec![Strategy::IgnoreKeys];
+        config.fallback.ignore_keys = Keys(Key::SideC.mask());
+        let mut haxo = instrument_with(&config);
+        assert!(haxo
+            .tick(LOW_A | Key::SideC.mask(), 40)
+            .contains(&Event::NoteOn { note: 69, vel: 127 }));
+        // Keys that cannot be resolved still hold the note
+        assert_eq!(haxo.tick(LOW_A | Key::F.mask(), 40), vec![]);
+        assert!(haxo.is_fingering(LOW_A | Key::SideC.mask()));
+        assert!(!haxo.is_fingering(LOW_A | Key::F.mask()));
+    }
+
+    #[test]
+    fn transpose_applies() {
+        let mut haxo = instrument();
+        haxo.notemap.transpose = -14;
+        assert_eq!(
+            haxo.tick(LOW_A, 40),
+            vec![Event::Breath(40), Event::NoteOn { note: 55, vel: 127 }]
+        );
+    }
+
+    #[test]
+    fn set_notemap() {
+        let mut haxo = instrument();
+        haxo.notemap.transpose = -14;
+        let notemap: BTreeMap<u32, i32> = [(LOW_A, 72)].iter().copied().collect();
+        haxo.set_notemap(NoteMap::from_map(notemap, 0));
+        assert!(!haxo.is_fingering(MID_B));
+        assert_eq!(
+            haxo.tick(LOW_A, 40),
+            vec![Event::Breath(40), Event::NoteOn { note: 58, vel: 127 }]
+        );
+    }
+
+    #[test]
+    fn control_mode() {
+        let mut haxo = instrument();
+        let hold = mode_change_ticks();
+        for _ in 1..hold {
+            haxo.tick(LOW_BB, -20);
+            assert_eq!(haxo.mode(), Mode::Play);
+        }
+        let events = haxo.tick(LOW_BB, -20);
+        assert_eq!(haxo.mode(), Mode::Control);
+        assert!(events.contains(&Event::NoteOn { note: 71, vel: 50 }));
+
+        // Keys do not play notes in control mode
+        let events = haxo.tick(LOW_A, 40);
+        assert!(!events.iter().any(is_note_event));
+
+        assert_eq!(haxo.take_action(), None);
+        haxo.tick(Key::LowCSharp.mask(), 0);
+        assert_eq!(haxo.take_action(), Some(Action::Calibrate));
+        assert_eq!(haxo.take_action(), None);
+        haxo.tick(Key::LowC.mask(), 0);
+        assert_eq!(haxo.take_action(), Some(Action::SwitchProfile(-1)));
+        let events = haxo.tick(Key::B.mask(), 0);
+        assert_eq!(events[0], Event::Gain(1.1));
+        assert_eq!(haxo.state().gain, Some(1.1));
+
+        // Letting go of a key of a chord runs nothing
+        haxo.tick(mask(&[Key::LowC, Key::LowCSharp]), 0);
+        haxo.tick(Key::LowC.mask(), 0);
+        assert_eq!(haxo.take_action(), None);
+
+        let events = haxo.tick(RETURN_TO_PLAY_KEYS, 0);
+        assert_eq!(haxo.mode(), Mode::Play);
+        assert!(events.contains(&Event::NoteOn { note: 70, vel: 50 }));
+    }
+
+    #[test]
+    fn control_mode_without_low_bb() {
+        let mut haxo = instrument();
+        let notemap: BTreeMap<u32, i32> = [(LOW_A, 69), (MID_B, 71)].iter().copied().collect();
+        haxo.set_notemap(NoteMap::from_map(notemap, 0));
+        for _ in 0..mode_change_ticks() {
+            haxo.tick(LOW_BB | Key::Bis.mask(), -20);
+        }
+        assert_eq!(haxo.mode(), Mode::Control);
+        haxo.tick(Key::LowEb.mask(), 0);
+        assert_eq!(haxo.take_action(), Some(Action::SwitchProfile(1)));
+    }
+
+    #[test]
+    fn mode_keys_are_fixed() {
+        let mut haxo = instrument();
+        // Low Bb played with another fingering
+        let notemap: BTreeMap<u32, i32> = [(LOW_A, 58), (MID_B, 59)].iter().copied().collect();
+        haxo.set_notemap(NoteMap::from_map(notemap, 0));
+        for _ in 0..mode_change_ticks() {
+            haxo.tick(LOW_A, -20);
+        }
+        assert_eq!(haxo.mode(), Mode::Play);
+        haxo.tick(MID_B, 0);
+        for _ in 0..mode_change_ticks() {
+            haxo.tick(MID_B, -20);
+        }
+        assert_eq!(haxo.mode(), Mode::Play);
+    }
+
+    #[test]
+    fn countdown_shorter_than_a_tick() {
+        let mut config = Config::default();
+        config.timing.neg_press_countdown_ms = 1;
+        let mut haxo = instrument_with(&config);
+        haxo.tick(LOW_BB, -20);
+        assert_eq!(haxo.mode(), Mode::Control);
+    }
+
+    #[test]
+    fn short_draw_keeps_play_mode() {
+        let mut haxo = instrument();
+        let hold = mode_change_ticks();
+        for _ in 1..hold {
+            haxo.tick(LOW_BB, -20);
+        }
+        haxo.tick(LOW_BB, 0);
+        haxo.tick(LOW_BB, -20);
+        assert_eq!(haxo.mode(), Mode::Play);
+    }
+
+    #[test]
+    fn transpose_mode() {
+        let mut haxo = instrument();
+        let hold = mode_change_ticks();
+        let mut events = Vec::new();
+        for _ in 0..hold {
+            events = haxo.tick(LOW_B, -20);
+        }
+        assert_eq!(haxo.mode(), Mode::Transpose);
+        assert_eq!(
+            events,
+            vec![
+                Event::Feedback(true),
+                Event::NoteOn { note: 71, vel: 50 },
+                Event::Breath(50),
+                Event::Pause(100),
+                Event::NoteOff { note: 71 },
+                Event::Feedback(false),
+                Event::Pause(20),
+                Event::Feedback(true),
+                Event::NoteOn { note: 75, vel: 50 },
+                Event::Breath(50),
+                Event::Pause(100),
+                Event::NoteOff { note: 75 },
+                Event::Feedback(false),
+            ]
+        );
+    }
+}
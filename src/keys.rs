@@ -0,0 +1,249 @@
+// Names for the physical keys.  The scanner reports pressed keys as a bitmap
+// with one bit per position of the HAT key matrix: row * 3 + column.
+// Positions 11 and 21 have no key.
+//
+// Bitmaps are written as key names joined by '+', lowest position first, for
+// example "b+a+g" for 0x2480.  Plain numbers, decimal or 0x hex, are accepted
+// wherever names are.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Key {
+    Octave = 0,
+    FrontF = 1,
+    PalmEb = 2,
+    SideE = 3,
+    Bis = 4,
+    PalmD = 5,
+    SideC = 6,
+    B = 7,
+    PalmF = 8,
+    SideBb = 9,
+    A = 10,
+    AltFSharp = 12,
+    G = 13,
+    GSharp = 14,
+    LowEb = 15,
+    F = 16,
+    LowCSharp = 17,
+    LowC = 18,
+    E = 19,
+    LowB = 20,
+    D = 22,
+    LowBb = 23,
+}
+
+impl Key {
+    pub const ALL: [Key; 22] = [
+        Key::Octave,
+        Key::FrontF,
+        Key::PalmEb,
+        Key::SideE,
+        Key::Bis,
+        Key::PalmD,
+        Key::SideC,
+        Key::B,
+        Key::PalmF,
+        Key::SideBb,
+        Key::A,
+        Key::AltFSharp,
+        Key::G,
+        Key::GSharp,
+        Key::LowEb,
+        Key::F,
+        Key::LowCSharp,
+        Key::LowC,
+        Key::E,
+        Key::LowB,
+        Key::D,
+        Key::LowBb,
+    ];
+
+    /// Position of the key in the scan bitmap.
+    pub const fn bit(self) -> u8 {
+        self as u8
+    }
+
+    pub const fn mask(self) -> u32 {
+        1 << self as u32
+    }
+
+    pub fn from_bit(bit: u8) -> Option<Key> {
+        Key::ALL.iter().copied().find(|key| key.bit() == bit)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::Octave => "octave",
+            Key::FrontF => "front-f",
+            Key::PalmEb => "palm-eb",
+            Key::SideE => "side-e",
+            Key::Bis => "bis",
+            Key::PalmD => "palm-d",
+            Key::SideC => "side-c",
+            Key::B => "b",
+            Key::PalmF => "palm-f",
+            Key::SideBb => "side-bb",
+            Key::A => "a",
+            Key::AltFSharp => "alt-f#",
+            Key::G => "g",
+            Key::GSharp => "g#",
+            Key::LowEb => "low-eb",
+            Key::F => "f",
+            Key::LowCSharp => "low-c#",
+            Key::LowC => "low-c",
+            Key::E => "e",
+            Key::LowB => "low-b",
+            Key::D => "d",
+            Key::LowBb => "low-bb",
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Key {
+    type Err = Box<dyn Error>;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Key::ALL
+            .iter()
+            .copied()
+            .find(|key| key.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unknown key '{}'", name).into())
+    }
+}
+
+/// Mask of all the given keys.
+pub const fn mask(keys: &[Key]) -> u32 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < keys.len() {
+        mask |= keys[i].mask();
+        i += 1;
+    }
+    mask
+}
+
+/// A key bitmap, for formatting and parsing.  In configuration files it is a
+/// list of key names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct Keys(pub u32);
+
+impl Keys {
+    /// The keys one by one, lowest position first.
+    pub fn split(self) -> impl Iterator<Item = Keys> {
+        (0..32)
+            .filter(move |bit| self.0 & (1 << bit) != 0)
+            .map(|bit| Keys(1 << bit))
+    }
+}
+
+impl TryFrom<Vec<String>> for Keys {
+    type Error = String;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        let mut bitmap = 0;
+        for name in names.iter() {
+            bitmap |= name.parse::<Keys>().map_err(|e| e.to_string())?.0;
+        }
+        Ok(Keys(bitmap))
+    }
+}
+
+impl From<Keys> for Vec<String> {
+    fn from(keys: Keys) -> Self {
+        keys.split().map(|key| key.to_string()).collect()
+    }
+}
+
+impl fmt::Display for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("none");
+        }
+        let mut first = true;
+        for bit in 0..32 {
+            if self.0 & (1 << bit) == 0 {
+                continue;
+            }
+            if !first {
+                f.write_str("+")?;
+            }
+            first = false;
+            match Key::from_bit(bit) {
+                Some(key) => write!(f, "{}", key)?,
+                None => write!(f, "bit{}", bit)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Keys {
+    type Err = Box<dyn Error>;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        if let Some(hex) = text.strip_prefix("0x") {
+            return Ok(Keys(u32::from_str_radix(hex, 16)?));
+        }
+        if let Ok(bitmap) = text.parse() {
+            return Ok(Keys(bitmap));
+        }
+        if text == "none" {
+            return Ok(Keys(0));
+        }
+        let mut bitmap = 0;
+        for name in text.split('+') {
+            let name = name.trim();
+            bitmap |= match name.strip_prefix("bit") {
+                Some(bit) => 1u32.checked_shl(bit.parse()?).ok_or("bit out of range")?,
+                None => name.parse::<Key>()?.mask(),
+            };
+        }
+        Ok(Keys(bitmap))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        for key in Key::ALL.iter() {
+            assert_eq!(key.name().parse::<Key>().unwrap(), *key);
+            assert_eq!(Key::from_bit(key.bit()), Some(*key));
+        }
+        assert_eq!(Key::from_bit(11), None);
+        assert_eq!(mask(&[Key::PalmEb, Key::PalmD, Key::PalmF]), 0x124);
+    }
+
+    #[test]
+    fn format_and_parse() -> Result<(), Box<dyn Error>> {
+        assert_eq!(Keys(0x2480).to_string(), "b+a+g");
+        assert_eq!(Keys(0xcd2480).to_string(), "b+a+g+f+low-c+e+d+low-bb");
+        assert_eq!(Keys(0x800).to_string(), "bit11");
+        assert_eq!(Keys(0).to_string(), "none");
+        for text in ["b+a+g", "B + A + G", "0x2480", "9344"].iter() {
+            assert_eq!(text.parse::<Keys>()?, Keys(0x2480));
+        }
+        assert_eq!("none".parse::<Keys>()?, Keys(0));
+        assert_eq!("octave+bit11".parse::<Keys>()?, Keys(0x801));
+        assert!("b+h".parse::<Keys>().is_err());
+        assert!("bit40".parse::<Keys>().is_err());
+        Ok(())
+    }
+}
@@ -1,30 +1,286 @@
+use std::collections::VecDeque;
 use std::error::Error;
+use std::fmt;
 use std::thread;
 use std::time::Duration;
 
-use rppal::gpio::Gpio;
-use rppal::gpio::Level;
+#[cfg(feature = "instrumentation")]
+use log::info;
+use rppal::gpio::{Gpio, InputPin, Level, OutputPin};
 
-use static_assertions::const_assert;
+use crate::config::MatrixConfig;
+use crate::keys::{Key, Keys};
 
-// BCM pin numbering
-const ROWS: [u8; 8] = [13, 12, 16, 17, 20, 22, 23, 24];
-const COLS: [u8; 3] = [25, 26, 27];
+/// A source of key matrix state.  Every call to `scan` returns a bitmap where
+/// a bit is set if the corresponding key is pressed.
+pub trait KeyScanner {
+    fn scan(&mut self) -> Result<u32, Box<dyn Error>>;
+}
+
+// Log the scan timing this often, in scans
+#[cfg(feature = "instrumentation")]
+const TIMING_INTERVAL: u64 = 60_000;
+
+/// Scans the key matrix wired to the Raspberry Pi GPIO pins.  The pins are
+/// acquired once, by `init_io`, and released when the scanner is dropped.
+pub struct Scanner {
+    rows: Vec<OutputPin>,
+    cols: Vec<InputPin>,
+    row_settle: Duration,
+}
+
+impl Scanner {
+    pub fn new(matrix: &MatrixConfig) -> Result<Self, Box<dyn Error>> {
+        init_io(matrix)
+    }
+
+    // Bitmap of the pressed keys, bit row * cols + col for each key
+    fn read(&mut self) -> u32 {
+        let mut keymap: u32 = 0;
+        let mut key_idx = 0;
+        for row_pin in self.rows.iter_mut() {
+            row_pin.set_low();
+            thread::sleep(self.row_settle);
+            for col_pin in self.cols.iter() {
+                if col_pin.read() == Level::Low {
+                    set_bit_at(&mut keymap, key_idx);
+                }
+                key_idx += 1;
+            }
+            row_pin.set_high();
+        }
+        keymap
+    }
+}
+
+impl KeyScanner for Scanner {
+    fn scan(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(self.read())
+    }
+}
+
+/// How long key matrix scans take, row settle time and debouncing included.
+#[cfg(feature = "instrumentation")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScanTiming {
+    pub scans: u64,
+    pub total: Duration,
+    pub max: Duration,
+    last_report: u64,
+}
+
+#[cfg(feature = "instrumentation")]
+impl ScanTiming {
+    /// Add the duration of one scan, logging the totals every so often.
+    pub fn record(&mut self, time: Duration) {
+        self.scans += 1;
+        self.total += time;
+        self.max = self.max.max(time);
+        if self.scans - self.last_report >= TIMING_INTERVAL {
+            info!("Key scan: {}", self);
+            self.last_report = self.scans;
+        }
+    }
+}
+
+#[cfg(feature = "instrumentation")]
+impl fmt::Display for ScanTiming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mean = self.total.as_micros() / u128::from(self.scans.max(1));
+        write!(
+            f,
+            "{} scans, mean {} us, max {} us",
+            self.scans,
+            mean,
+            self.max.as_micros()
+        )
+    }
+}
+
+/// Replays a sequence of key bitmaps from memory, one per scan.  Once the
+/// sequence is exhausted the last bitmap keeps being returned, as if the
+/// player held the keys in place.
+pub struct ScriptedScanner {
+    pending: VecDeque<u32>,
+    last: u32,
+}
 
-const ROW_PULL_DOWN_TIME_US: u64 = 10;
+impl ScriptedScanner {
+    pub fn new<I: IntoIterator<Item = u32>>(keys: I) -> Self {
+        ScriptedScanner {
+            pending: keys.into_iter().collect(),
+            last: 0,
+        }
+    }
+
+    /// Queue another bitmap to be returned after the ones already queued.
+    pub fn push(&mut self, keys: u32) {
+        self.pending.push_back(keys);
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl KeyScanner for ScriptedScanner {
+    fn scan(&mut self) -> Result<u32, Box<dyn Error>> {
+        if let Some(keys) = self.pending.pop_front() {
+            self.last = keys;
+        }
+        Ok(self.last)
+    }
+}
+
+/// Filters out contact bounce.  A key only changes state once the scanner has
+/// reported the new state for a number of consecutive scans.
+pub struct Debouncer<S: KeyScanner> {
+    scanner: S,
+    scans: u32,
+    keys: u32,
+    counts: [u32; 32],
+}
+
+impl<S: KeyScanner> Debouncer<S> {
+    pub fn new(scanner: S, scans: u32) -> Self {
+        Debouncer {
+            scanner,
+            scans,
+            keys: 0,
+            counts: [0; 32],
+        }
+    }
+}
+
+impl<S: KeyScanner> KeyScanner for Debouncer<S> {
+    fn scan(&mut self) -> Result<u32, Box<dyn Error>> {
+        let raw = self.scanner.scan()?;
+        for (bit, count) in self.counts.iter_mut().enumerate() {
+            let mask = 1 << bit;
+            if (raw ^ self.keys) & mask == 0 {
+                *count = 0;
+                continue;
+            }
+            *count += 1;
+            if *count >= self.scans {
+                self.keys ^= mask;
+                *count = 0;
+            }
+        }
+        Ok(self.keys)
+    }
+}
+
+/// A key, by its bit number in the scan bitmap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KeyId(pub u8);
+
+impl KeyId {
+    pub fn mask(&self) -> u32 {
+        1 << self.0
+    }
 
-pub fn init_io() -> Result<(), Box<dyn Error>> {
+    /// The physical key at this position, if there is one.
+    pub fn key(&self) -> Option<Key> {
+        Key::from_bit(self.0)
+    }
+}
+
+impl fmt::Display for KeyId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "key {}", Keys(self.mask()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyEvent {
+    Pressed(KeyId),
+    Released(KeyId),
+}
+
+impl fmt::Display for KeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyEvent::Pressed(key) => write!(f, "pressed {}", key),
+            KeyEvent::Released(key) => write!(f, "released {}", key),
+        }
+    }
+}
+
+/// The keys held at one scan, and the changes since the previous one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyScan {
+    pub time: Duration,
+    pub keys: u32,
+    pub events: Vec<KeyEvent>,
+}
+
+/// Turns successive key bitmaps into press and release events.  Timestamps
+/// are supplied by the caller, so simulated time works as well as real time.
+#[derive(Default)]
+pub struct KeyTracker {
+    keys: u32,
+}
+
+impl KeyTracker {
+    pub fn new() -> Self {
+        KeyTracker { keys: 0 }
+    }
+
+    /// Scan the keys and report what changed since the previous scan.
+    pub fn scan(
+        &mut self,
+        scanner: &mut dyn KeyScanner,
+        time: Duration,
+    ) -> Result<KeyScan, Box<dyn Error>> {
+        let keys = scanner.scan()?;
+        Ok(self.update(keys, time))
+    }
+
+    /// Report the changes from the previous bitmap to this one, lowest key
+    /// first.
+    pub fn update(&mut self, keys: u32, time: Duration) -> KeyScan {
+        let changed = keys ^ self.keys;
+        let events = (0..32)
+            .map(KeyId)
+            .filter(|key| changed & key.mask() != 0)
+            .map(|key| {
+                if keys & key.mask() != 0 {
+                    KeyEvent::Pressed(key)
+                } else {
+                    KeyEvent::Released(key)
+                }
+            })
+            .collect();
+        self.keys = keys;
+        KeyScan { time, keys, events }
+    }
+}
+
+/// Acquire the matrix pins.  Rows are outputs, idle high, and columns are
+/// inputs that read low while the key at the driven row is pressed.
+pub fn init_io(matrix: &MatrixConfig) -> Result<Scanner, Box<dyn Error>> {
     let gpio = Gpio::new()?;
-    for col in &COLS {
-        let mut pin = gpio.get(*col)?.into_input_pullup();
-        pin.set_reset_on_drop(false);
+    let mut cols = Vec::with_capacity(matrix.cols.len());
+    for col in &matrix.cols {
+        let pin = gpio.get(*col)?;
+        cols.push(if matrix.pull_up {
+            pin.into_input_pullup()
+        } else {
+            pin.into_input()
+        });
     }
-    for row in &ROWS {
+    let mut rows = Vec::with_capacity(matrix.rows.len());
+    for row in &matrix.rows {
         let mut pin = gpio.get(*row)?.into_output();
         pin.set_high();
-        pin.set_reset_on_drop(false);
+        rows.push(pin);
     }
-    Ok(())
+    Ok(Scanner {
+        rows,
+        cols,
+        row_settle: Duration::from_micros(matrix.row_settle_us.into()),
+    })
 }
 
 fn get_bit_at(input: u32, n: u8) -> bool {
@@ -41,69 +297,35 @@ fn set_bit_at(output: &mut u32, n: u8) {
     }
 }
 
-fn clear_bit_at(output: &mut u32, n: u8) {
-    if n < 32 {
-        *output &= !(1 << n);
-    }
-}
-
-pub fn scan() -> Result<u32, Box<dyn Error>> {
-    const_assert!(ROWS.len() + COLS.len() <= 32);
-    let gpio = Gpio::new()?;
-    let mut key_idx = 0;
-    // a bit if set if the corresponding key is pressed
-    let mut keymap: u32 = 0;
-    for row in &ROWS {
-        let mut row_pin = gpio.get(*row)?.into_output();
-        row_pin.set_low();
-        thread::sleep(Duration::from_micros(ROW_PULL_DOWN_TIME_US));
-
-        for col in &COLS {
-            let col_pin = gpio.get(*col)?;
-            let is_pressed = col_pin.read() == Level::Low;
-
-            if get_bit_at(keymap, key_idx) != is_pressed {
-                if is_pressed {
-                    set_bit_at(&mut keymap, key_idx);
-                } else {
-                    clear_bit_at(&mut keymap, key_idx);
-                }
-            }
-            key_idx += 1;
-        }
-        row_pin.set_high();
-    }
-    Ok(keymap)
-}
-
 #[allow(dead_code)]
-pub fn debug_print(keys: u32) {
+pub fn debug_print(keys: u32, matrix: &MatrixConfig) {
     println!("");
     print!("  ");
-    for _col in &COLS {
+    for _col in &matrix.cols {
         print!("==");
     }
     println!("");
     print!("   ");
-    for (i, _col) in COLS.iter().enumerate() {
+    for (i, _col) in matrix.cols.iter().enumerate() {
         print!("{} ", i);
     }
     println!("");
     print!("  ");
-    for _col in &COLS {
+    for _col in &matrix.cols {
         print!("==");
     }
     println!("");
-    for (ir, _) in ROWS.iter().enumerate() {
-        for (ic, _) in COLS.iter().enumerate() {
+    for (ir, _) in matrix.rows.iter().enumerate() {
+        for (ic, _) in matrix.cols.iter().enumerate() {
             if ic == 0 {
                 print!("{}: ", ir);
             }
-            let key = get_bit_at(keys, (ir * COLS.len() + ic) as u8);
+            let key = get_bit_at(keys, (ir * matrix.cols.len() + ic) as u8);
             print!("{} ", if key { "x" } else { "o" });
         }
         println!("");
     }
+    println!("{}", Keys(keys));
 }
 
 #[cfg(test)]
@@ -116,14 +338,68 @@ mod tests {
 
     #[test]
     fn init() -> Result<(), Box<dyn Error>> {
-        init_io().expect("Failed to initialize scan GPIO");
+        init_io(&MatrixConfig::default()).expect("Failed to initialize scan GPIO");
         Ok(())
     }
 
     #[test]
     fn read() -> Result<(), Box<dyn Error>> {
-        init_io().expect("Failed to initialize scan GPIO");
-        let _keys = scan()?;
+        let mut scanner =
+            init_io(&MatrixConfig::default()).expect("Failed to initialize scan GPIO");
+        let _keys = scanner.scan()?;
+        Ok(())
+    }
+
+    #[test]
+    fn scripted() -> Result<(), Box<dyn Error>> {
+        let mut scanner = ScriptedScanner::new(vec![0x1, 0x124]);
+        assert_eq!(scanner.scan()?, 0x1);
+        assert_eq!(scanner.scan()?, 0x124);
+        assert!(scanner.is_exhausted());
+        // Last bitmap is held once the script runs out
+        assert_eq!(scanner.scan()?, 0x124);
+        scanner.push(0);
+        assert_eq!(scanner.scan()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn key_events() -> Result<(), Box<dyn Error>> {
+        let mut scanner = ScriptedScanner::new(vec![0x480, 0x480, 0x81]);
+        let mut tracker = KeyTracker::new();
+        let scan = tracker.scan(&mut scanner, Duration::from_millis(2))?;
+        assert_eq!(
+            scan,
+            KeyScan {
+                time: Duration::from_millis(2),
+                keys: 0x480,
+                events: vec![KeyEvent::Pressed(KeyId(7)), KeyEvent::Pressed(KeyId(10))],
+            }
+        );
+        assert!(tracker
+            .scan(&mut scanner, Duration::from_millis(4))?
+            .events
+            .is_empty());
+        let scan = tracker.scan(&mut scanner, Duration::from_millis(6))?;
+        assert_eq!(
+            scan.events,
+            vec![KeyEvent::Pressed(KeyId(0)), KeyEvent::Released(KeyId(10))]
+        );
+        assert_eq!(scan.events[1].to_string(), "released key a");
+        Ok(())
+    }
+
+    #[test]
+    fn debounce() -> Result<(), Box<dyn Error>> {
+        let raw = vec![0x1, 0x0, 0x1, 0x1, 0x3, 0x3, 0x2, 0x3, 0x2, 0x2];
+        let mut scanner = Debouncer::new(ScriptedScanner::new(raw), 2);
+        let keys: Vec<u32> = (0..10).map(|_| scanner.scan().unwrap()).collect();
+        assert_eq!(keys, vec![0x0, 0x0, 0x0, 0x1, 0x1, 0x3, 0x3, 0x3, 0x3, 0x2]);
+
+        // A single scan means no debouncing
+        let mut scanner = Debouncer::new(ScriptedScanner::new(vec![0x1, 0x0]), 1);
+        assert_eq!(scanner.scan()?, 0x1);
+        assert_eq!(scanner.scan()?, 0x0);
         Ok(())
     }
 
@@ -136,13 +412,14 @@ mod tests {
     #[test]
     #[ignore]
     fn all_keys() -> Result<(), Box<dyn Error>> {
-        const NUM_KEYS: u32 = 22;
+        const NUM_KEYS: u32 = Key::ALL.len() as u32;
         println!("Press all the keys at least once, in any order...");
-        init_io().expect("Failed to initialize scan GPIO");
+        let matrix = MatrixConfig::default();
+        let mut scanner = init_io(&matrix).expect("Failed to initialize scan GPIO");
         let mut detected_keys: u32 = 0;
         let mut last_keys: u32 = 0;
         for _ in 0..5000 {
-            let keys = scan()?;
+            let keys = scanner.scan()?;
             thread::sleep(Duration::from_millis(50));
             detected_keys |= keys;
             if last_keys != keys {
@@ -152,10 +429,10 @@ mod tests {
                     NUM_KEYS,
                     detected_keys,
                     keys,
-                    keys
+                    Keys(keys)
                 );
                 last_keys = keys;
-                debug_print(detected_keys);
+                debug_print(detected_keys, &matrix);
             }
             if detected_keys.count_ones() == NUM_KEYS {
                 return Ok(());
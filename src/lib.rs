@@ -0,0 +1,27 @@
+// Library side of the haxophone application: everything needed to turn key
+// and breath readings into notes, independent of how they are wired together
+// by the haxo001 binary.
+
+mod alsa;
+pub mod calibrate;
+mod commands;
+pub mod config;
+pub mod curve;
+pub mod diagnostics;
+pub mod fallback;
+pub mod filter;
+pub mod instrument;
+pub mod keys;
+pub mod keyscan;
+#[cfg(feature = "midi")]
+pub mod midi;
+pub mod midinotes;
+pub mod notemap;
+pub mod pressure;
+pub mod profiles;
+pub mod rules;
+pub mod simulate;
+pub mod sink;
+pub mod state;
+pub mod synth;
+mod transpose;
@@ -1,51 +1,89 @@
-use log::{debug, info, log_enabled, Level};
+use log::{debug, info, warn};
 
 #[cfg(feature = "instrumentation")]
 use rppal::gpio::Gpio;
 
-use std::cmp::max;
 use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 use std::process::Command;
-use std::thread;
 use std::time::Duration;
+#[cfg(feature = "instrumentation")]
+use std::time::Instant;
 
 use schedule_recv::periodic;
 
 use structopt::StructOpt;
 
-mod alsa;
-mod commands;
-mod keyscan;
+use haxo::calibrate::{Calibration, Calibrator};
+use haxo::config::{self, Config, TimingConfig};
+use haxo::diagnostics::{self, FaultAlarm, KeyDiagnostics};
+use haxo::instrument::{Action, Instrument};
+#[cfg(feature = "instrumentation")]
+use haxo::keyscan::ScanTiming;
+use haxo::keyscan::{self, Debouncer, KeyScanner, KeyTracker};
 #[cfg(feature = "midi")]
-mod midi;
-mod midinotes;
-mod notemap;
-mod pressure;
-mod synth;
-mod transpose;
-
-use crate::synth::beep;
+use haxo::midi;
+use haxo::notemap::{self, Severity};
+use haxo::pressure::{self, BreathSensor};
+use haxo::profiles::{Loaded, ProfileLoader, Profiles};
+#[cfg(feature = "instrumentation")]
+use haxo::sink::Event;
+use haxo::sink::{self, beep, SoundSink};
+use haxo::state::{State, StateFile};
+use haxo::{simulate, synth};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "haxo", about = "Make music on a haxophone", version = env!("VERGEN_GIT_DESCRIBE"), settings = &[structopt::clap::AppSettings::AllowNegativeNumbers])]
 struct Opt {
+    /// Configuration file [default: /etc/haxo/haxo.toml, if present]
+    #[structopt(short, long)]
+    config: Option<String>,
+    /// Print the effective configuration and exit
+    #[structopt(long)]
+    print_config: bool,
     #[structopt(short, long)]
     record: bool,
-    #[structopt(short, long, default_value = "/usr/share/sounds/sf2/FluidR3_GM.sf2")]
-    sf2_file: String,
-    #[structopt(short, long, default_value = "67")]
-    prog_number: i32,
-    #[structopt(short, long, default_value = "./notemap.json")]
-    notemap_file: String,
-    #[structopt(short, long, default_value = "-14")]
-    transpose: i32,
+    #[structopt(short, long)]
+    sf2_file: Option<String>,
+    #[structopt(short, long)]
+    prog_number: Option<i32>,
+    #[structopt(short, long)]
+    notemap_file: Option<String>,
+    /// Notemap profile to play with, from the profiles directory
+    #[structopt(long, conflicts_with = "notemap-file")]
+    profile: Option<String>,
+    #[structopt(short, long)]
+    transpose: Option<i32>,
+    /// Run without hardware, reading keys and pressure from a script file
+    #[structopt(long)]
+    simulate: Option<String>,
+    /// Write simulated events to this file instead of stdout
+    #[structopt(long, requires = "simulate")]
+    output: Option<String>,
+    #[structopt(subcommand)]
+    cmd: Option<Subcommand>,
 }
 
-#[derive(PartialEq)]
-enum Mode {
-    Play,
-    Control,
-    Transpose,
+#[derive(Debug, StructOpt)]
+enum Subcommand {
+    /// Measure your breath levels and save them to the state file
+    Calibrate,
+    /// Check or convert notemap files
+    Notemap(NotemapCommand),
+}
+
+#[derive(Debug, StructOpt)]
+enum NotemapCommand {
+    /// Report problems in a notemap file [default: the configured notemap]
+    Check { file: Option<String> },
+    /// Convert a notemap file between the legacy JSON format and the version 2
+    /// TOML format.  The format written depends on the extension of the output
+    /// file: version 2 for .toml, legacy otherwise.
+    Convert { input: String, output: String },
+    /// List the notemap profiles of the profiles directory
+    Profiles,
 }
 
 #[allow(dead_code)]
@@ -57,162 +95,428 @@ fn shutdown() {
         .expect("failed to halt system");
 }
 
-const TICK_USECS: u32 = 2_000;
-
 #[cfg(feature = "instrumentation")]
 const GPIO_UART_RXD: u8 = 15;
 #[cfg(feature = "instrumentation")]
 const GPIO_UART_TXD: u8 = 14;
 
+const PROFILE_BEEP_NOTE: i32 = 76;
+
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
     let opt = Opt::from_args();
     debug!("{:?}", opt);
 
-    let (synth, _settings, _adriver) = synth::try_init(&opt.sf2_file, opt.prog_number);
+    let mut config = load_config(&opt)?;
+    // Simulations ignore the saved state so that they are reproducible
+    let mut statefile = match &opt.simulate {
+        None if !config.state.file.is_empty() => Some(StateFile::open(&config.state.file)),
+        _ => None,
+    };
+    if let Some(statefile) = &statefile {
+        statefile.state().apply(&mut config);
+    }
+    apply_flags(&opt, &mut config)?;
+    if !config.matrix.has_default_layout() {
+        warn!(
+            "Key matrix is {}x{}, not 8x3: control, transpose and profile keys assume the Haxophone HAT",
+            config.matrix.rows.len(),
+            config.matrix.cols.len()
+        );
+    }
+    if opt.print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+    select_profile(&mut config);
+    debug!("{:?}", config);
+    if let Some(Subcommand::Notemap(cmd)) = &opt.cmd {
+        return notemap_command(cmd, &config);
+    }
+    let tick_usecs = config.timing.tick_usecs;
+
+    if let Some(scriptfile) = &opt.simulate {
+        if let Some(Subcommand::Calibrate) = opt.cmd {
+            return Err("Calibration needs the breath sensor, it cannot be simulated".into());
+        }
+        let script = simulate::Script::load(scriptfile)?;
+        let (scanner, mut sensor) = script.inputs(tick_usecs);
+        let debounce = config.timing.ms_to_ticks(config.timing.debounce_ms);
+        let mut scanner = Debouncer::new(scanner, debounce);
+        let notemap = notemap::NoteMap::generate(
+            &config.notemap.file,
+            config.notemap.transpose,
+            &config.matrix,
+        )?;
+        let mut instrument = Instrument::new(notemap, &config);
+        let out: Box<dyn Write> = match &opt.output {
+            Some(outfile) => Box::new(BufWriter::new(File::create(outfile)?)),
+            None => Box::new(io::stdout()),
+        };
+        let mut sink = simulate::EventWriter::new(out, tick_usecs);
+        return run(
+            &mut scanner,
+            &mut sensor,
+            &mut sink,
+            &mut instrument,
+            None,
+            &config,
+            Some(script.ticks(tick_usecs)),
+        );
+    }
+
+    let (mut synth, _settings, _adriver) = synth::try_init(&config.synth);
     #[cfg(feature = "midi")]
     let mut midi_out = midi::MidiOut::new()?;
-
-    let tick = periodic(Duration::from_micros(TICK_USECS as u64));
-    // Use UART RXD pin to monitor timing of periodic task.  This is easily
-    // accessible on the haxophone HAT when the console is disabled.
-    #[cfg(feature = "instrumentation")]
-    let mut busy_pin = Gpio::new()?.get(GPIO_UART_RXD)?.into_output();
-    #[cfg(feature = "instrumentation")]
-    let mut noteon_pin = Gpio::new()?.get(GPIO_UART_TXD)?.into_output();
+    let mut sink = sink::FanOut::new();
+    sink.add(&mut synth);
+    #[cfg(feature = "midi")]
+    sink.add(&mut midi_out);
 
     println!(
         "Starting haxophone (version {})...",
         env!("VERGEN_GIT_DESCRIBE")
     );
 
-    keyscan::init_io().expect("Failed to initialize scan GPIO");
-    let mut sensor = pressure::Pressure::init().expect("Failed to initialize pressure sensor");
+    let scanner = keyscan::Scanner::new(&config.matrix).expect("Failed to initialize scan GPIO");
+    let debounce = config.timing.ms_to_ticks(config.timing.debounce_ms);
+    let mut scanner = Debouncer::new(scanner, debounce);
+    let mut sensor = pressure::Pressure::init(&config.pressure, &config.timing)
+        .expect("Failed to initialize pressure sensor");
 
-    let mut notemap = notemap::NoteMap::generate(&opt.notemap_file, opt.transpose);
+    if let Some(Subcommand::Calibrate) = opt.cmd {
+        return calibrate(&mut sensor, &mut sink, &config.timing, statefile.as_mut());
+    }
+
+    let mut notemap = notemap::NoteMap::generate(
+        &config.notemap.file,
+        config.notemap.transpose,
+        &config.matrix,
+    )?;
     if opt.record {
         notemap.start_recording();
     }
+    let mut instrument = Instrument::new(notemap, &config);
 
-    let mut last_note = 0;
-    let mut mode = Mode::Play;
-    let mut cmd = commands::Command::new(&synth, opt.prog_number);
-
-    const TRANSPOSE_COUNTDOWN_MS: u32 = 200u32;
-    const TRANSPOSE_COUNTDOWN_TICKS: u32 = TRANSPOSE_COUNTDOWN_MS * 1000 / TICK_USECS;
-    let mut transpose = transpose::Transpose::new(&synth, TRANSPOSE_COUNTDOWN_TICKS);
+    run(
+        &mut scanner,
+        &mut sensor,
+        &mut sink,
+        &mut instrument,
+        statefile.as_mut(),
+        &config,
+        None,
+    )
+}
 
-    const NEG_PRESS_COUNTDOWN_MS: u32 = 500u32;
-    const NEG_PRESS_INIT_VAL: u32 = NEG_PRESS_COUNTDOWN_MS * 1000 / TICK_USECS;
-    let mut neg_pressure_countdown: u32 = NEG_PRESS_INIT_VAL;
-    let mut last_vol: i32 = 0;
-    loop {
-        tick.recv().unwrap();
-        #[cfg(feature = "instrumentation")]
-        busy_pin.set_high();
+// Read the configuration file, if any.
+fn load_config(opt: &Opt) -> Result<Config, Box<dyn Error>> {
+    match &opt.config {
+        Some(configfile) => Config::load(configfile),
+        None if Path::new(config::DEFAULT_CONFIG_FILE).exists() => {
+            Config::load(config::DEFAULT_CONFIG_FILE)
+        }
+        None => Ok(Config::default()),
+    }
+}
 
-        let keys = keyscan::scan()?;
-        let pressure = sensor.read()?;
-        let vol = max(0, pressure);
-        const MIDI_CC_VOLUME: i32 = 7;
-        const MIDI_CC_BREATH: i32 = 2;
-        if last_vol != vol {
-            synth.cc(0, MIDI_CC_VOLUME, vol);
-            #[cfg(feature = "midi")]
-            midi_out.cc(MIDI_CC_BREATH, vol);
-            last_vol = vol;
+// Command line flags take precedence over everything else.
+fn apply_flags(opt: &Opt, config: &mut Config) -> Result<(), Box<dyn Error>> {
+    if let Some(sf2_file) = &opt.sf2_file {
+        config.synth.sf2_file = sf2_file.clone();
+    }
+    if let Some(prog_number) = opt.prog_number {
+        config.synth.prog_number = prog_number;
+    }
+    if let Some(notemap_file) = &opt.notemap_file {
+        config.notemap.file = notemap_file.clone();
+        config.notemap.profile.clear();
+    }
+    if let Some(profile) = &opt.profile {
+        let dir = &config.notemap.profiles_dir;
+        if Profiles::scan(dir).file(profile).is_none() {
+            return Err(format!("No notemap profile '{}' in {}", profile, dir).into());
         }
+        config.notemap.profile = profile.clone();
+    }
+    if let Some(transpose) = opt.transpose {
+        config.notemap.transpose = transpose;
+    }
+    config.validate()
+}
 
-        if notemap.is_recording() {
-            notemap.record(keys, pressure);
+// Play with the notemap of the selected profile.  A saved profile may have
+// been removed since, which is no reason not to start.
+fn select_profile(config: &mut Config) {
+    let notemap = &mut config.notemap;
+    if notemap.profile.is_empty() {
+        return;
+    }
+    match Profiles::scan(&notemap.profiles_dir).file(&notemap.profile) {
+        Some(file) => notemap.file = file,
+        None => {
+            warn!(
+                "No notemap profile '{}' in {}, using {}",
+                notemap.profile, notemap.profiles_dir, notemap.file
+            );
+            notemap.profile.clear();
         }
+    }
+}
 
-        if mode == Mode::Control {
-            cmd.process(keys);
-        } else if mode == Mode::Transpose {
-            transpose.process(keys, vol, &mut notemap);
+fn notemap_command(cmd: &NotemapCommand, config: &Config) -> Result<(), Box<dyn Error>> {
+    match cmd {
+        NotemapCommand::Check { file } => {
+            let file = file.as_ref().unwrap_or(&config.notemap.file);
+            let contents =
+                fs::read_to_string(file).map_err(|e| format!("Failed to read {}: {}", file, e))?;
+            let issues = notemap::check(&contents, &config.matrix);
+            for issue in issues.iter() {
+                println!("{}: {}", file, issue);
+            }
+            let errors = issues
+                .iter()
+                .filter(|issue| issue.severity == Severity::Error)
+                .count();
+            println!("{} errors, {} warnings", errors, issues.len() - errors);
+            if errors > 0 {
+                return Err(format!("{} cannot be used", file).into());
+            }
+            Ok(())
         }
+        NotemapCommand::Convert { input, output } => {
+            let notemap = notemap::NoteMap::load(input, 0)?;
+            notemap.save_as(output, notemap::Format::for_file(output))
+        }
+        NotemapCommand::Profiles => {
+            let profiles = Profiles::scan(&config.notemap.profiles_dir);
+            for name in profiles.names() {
+                let current = if name == config.notemap.profile {
+                    "*"
+                } else {
+                    " "
+                };
+                println!("{} {}", current, name);
+            }
+            Ok(())
+        }
+    }
+}
 
-        if mode != Mode::Play {
-            // All three left hand palm keys pressed at once
-            if keys == 0x124 {
-                beep(&synth, 70, 50);
-                mode = Mode::Play;
-                info!("Return to Play Mode");
+// Play with a profile loaded on request.  A high beep confirms the switch, a
+// low one tells it failed.
+fn switch_profile(
+    instrument: &mut Instrument,
+    profile: &mut String,
+    loaded: Loaded,
+    sink: &mut dyn SoundSink,
+    statefile: Option<&mut StateFile>,
+) {
+    match loaded {
+        Ok((name, file, notemap)) => {
+            instrument.set_notemap(notemap);
+            info!("Notemap profile {} ({})", name, file);
+            beep(sink, PROFILE_BEEP_NOTE, 50);
+            if let Some(statefile) = statefile {
+                statefile.update(&State {
+                    profile: Some(name.clone()),
+                    ..State::default()
+                });
             }
-            continue;
+            *profile = name;
         }
+        Err(e) => {
+            warn!("{}", e);
+            beep(sink, diagnostics::ALARM_NOTE, 50);
+        }
+    }
+}
 
-        if let Some(note) = notemap.get(&keys) {
-            if last_note != note {
-                if log_enabled!(Level::Debug) {
-                    debug!(
-                        "Note: {} Pressure: {} Key {:032b}: {}",
-                        notemap.get_name(&note).unwrap_or("Unknown?"),
-                        pressure,
-                        keys,
-                        keys
-                    );
-                };
-                if vol > 0 {
-                    if last_note > 0 {
-                        synth.cc(0, MIDI_CC_VOLUME, 0);
-                        synth.noteoff(0, last_note);
-                        #[cfg(feature = "midi")]
-                        midi_out.noteoff(last_note);
-                        #[cfg(feature = "instrumentation")]
-                        noteon_pin.set_low();
-                        synth.cc(0, MIDI_CC_VOLUME, vol);
-                    }
-                    synth.noteon(0, note, 127);
-                    #[cfg(feature = "midi")]
-                    midi_out.noteon(note, 127);
-                    #[cfg(feature = "instrumentation")]
-                    noteon_pin.set_high();
-                    last_note = note;
-                    debug!("last_note changed to {}", last_note);
+// Calibration subcommand: guide the player through the calibration steps and
+// save the result.
+fn calibrate(
+    sensor: &mut dyn BreathSensor,
+    sink: &mut dyn SoundSink,
+    timing: &TimingConfig,
+    statefile: Option<&mut StateFile>,
+) -> Result<(), Box<dyn Error>> {
+    if statefile.is_none() {
+        println!("No state file configured, the calibration will not be saved");
+    }
+    let tick = periodic(Duration::from_micros(timing.tick_usecs as u64));
+    let mut calibrator = Calibrator::new(timing);
+    println!("{}", calibrator.phase().prompt());
+    loop {
+        tick.recv().unwrap();
+        sink.tick();
+        let raw = sensor.read_raw()?;
+        if let Some(outcome) = calibration_tick(&mut calibrator, raw, sink) {
+            return finish_calibration(outcome, sensor, statefile);
+        }
+    }
+}
+
+// Feed one raw reading to the calibration and tell the player what to do
+// next.  Returns the outcome once the calibration is over.
+fn calibration_tick(
+    calibrator: &mut Calibrator,
+    raw: i32,
+    sink: &mut dyn SoundSink,
+) -> Option<Result<Calibration, String>> {
+    let phase = calibrator.phase();
+    for event in calibrator.tick(raw) {
+        event.apply(sink);
+    }
+    if calibrator.phase() != phase {
+        println!("{}", calibrator.phase().prompt());
+    }
+    calibrator.outcome()
+}
+
+fn finish_calibration(
+    outcome: Result<Calibration, String>,
+    sensor: &mut dyn BreathSensor,
+    statefile: Option<&mut StateFile>,
+) -> Result<(), Box<dyn Error>> {
+    let calibration = outcome?;
+    println!("Breath levels: {}", calibration);
+    sensor.calibrate(&calibration);
+    if let Some(statefile) = statefile {
+        statefile.update(&State {
+            calibration: Some(calibration),
+            ..State::default()
+        });
+    }
+    Ok(())
+}
+
+// Play loop.  Runs in real time, one scan every tick, until an error occurs.
+// When a number of ticks is given, that many scans are run back to back
+// instead, as fast as possible.  Settings changed while playing are saved to
+// the state file, if there is one.
+fn run(
+    scanner: &mut dyn KeyScanner,
+    sensor: &mut dyn BreathSensor,
+    sink: &mut dyn SoundSink,
+    instrument: &mut Instrument,
+    mut statefile: Option<&mut StateFile>,
+    config: &Config,
+    ticks: Option<u64>,
+) -> Result<(), Box<dyn Error>> {
+    let timing = &config.timing;
+    let tick = match ticks {
+        Some(_) => None,
+        None => Some(periodic(Duration::from_micros(timing.tick_usecs as u64))),
+    };
+    // Use UART RXD pin to monitor timing of periodic task.  This is easily
+    // accessible on the haxophone HAT when the console is disabled.
+    #[cfg(feature = "instrumentation")]
+    let mut busy_pin = Gpio::new()?.get(GPIO_UART_RXD)?.into_output();
+    #[cfg(feature = "instrumentation")]
+    let mut noteon_pin = Gpio::new()?.get(GPIO_UART_TXD)?.into_output();
+    // Logged along with what the busy pin shows on a scope
+    #[cfg(feature = "instrumentation")]
+    let mut scan_timing = ScanTiming::default();
+
+    let mut tracker = KeyTracker::new();
+    let mut diagnostics = KeyDiagnostics::new(&config.matrix, &config.diagnostics, timing);
+    let mut alarm = FaultAlarm::new(timing);
+    let mut settings = instrument.state();
+    let mut profile = config.notemap.profile.clone();
+    let mut loader = ProfileLoader::new(&config.notemap.profiles_dir, &config.matrix);
+    // Calibration in progress, instead of playing
+    let mut calibrator: Option<Calibrator> = None;
+    let mut elapsed: u64 = 0;
+    loop {
+        match (&tick, ticks) {
+            (Some(tick), _) => tick.recv().unwrap(),
+            (None, Some(ticks)) if elapsed == ticks => return Ok(()),
+            _ => {}
+        }
+        elapsed += 1;
+        sink.tick();
+        #[cfg(feature = "instrumentation")]
+        busy_pin.set_high();
+
+        // Same clock as the simulation output, starting at the first tick
+        let time_us = (elapsed - 1) * timing.tick_usecs as u64;
+        #[cfg(feature = "instrumentation")]
+        let scan_start = Instant::now();
+        let scan = tracker.scan(scanner, Duration::from_micros(time_us))?;
+        #[cfg(feature = "instrumentation")]
+        scan_timing.record(scan_start.elapsed());
+        for event in scan.events.iter() {
+            debug!("{}.{:03} {}", time_us / 1000, time_us % 1000, event);
+        }
+        let keys = scan.keys;
+        for fault in diagnostics.check(keys, instrument.is_fingering(keys)) {
+            warn!("Key matrix: {}", fault);
+            if config.diagnostics.beep {
+                alarm.raise();
+            }
+        }
+        if let Some(calibration) = calibrator.as_mut() {
+            alarm.tick(None, instrument.volume(), sink);
+            let raw = sensor.read_raw()?;
+            if let Some(outcome) = calibration_tick(calibration, raw, sink) {
+                if let Err(e) = finish_calibration(outcome, sensor, statefile.as_deref_mut()) {
+                    warn!("{}", e);
                 }
+                calibrator = None;
             }
-            if vol <= 0 && last_note > 0 {
-                synth.noteoff(0, last_note);
-                #[cfg(feature = "midi")]
-                midi_out.noteoff(last_note);
-                #[cfg(feature = "instrumentation")]
-                noteon_pin.set_low();
-                last_note = 0;
+            #[cfg(feature = "instrumentation")]
+            busy_pin.set_low();
+            continue;
+        }
+
+        let pressure = sensor.read()?;
+        for event in instrument.tick(&scan, pressure) {
+            #[cfg(feature = "instrumentation")]
+            match event {
+                Event::NoteOn { .. } => noteon_pin.set_high(),
+                Event::NoteOff { .. } => noteon_pin.set_low(),
+                _ => {}
             }
+            event.apply(sink);
+        }
+        alarm.tick(instrument.note(), instrument.volume(), sink);
+        sensor.track_baseline(instrument.is_sounding());
 
-            // Negative pressure needs to hold for a minimum duration to trigger a mode change
-            if pressure < -10 {
-                neg_pressure_countdown = neg_pressure_countdown.wrapping_sub(1);
-            } else {
-                neg_pressure_countdown = NEG_PRESS_INIT_VAL;
+        let current = instrument.state();
+        if current != settings {
+            if let Some(statefile) = statefile.as_mut() {
+                statefile.update(&settings.changes(&current));
             }
+            settings = current;
+        }
 
-            // Enter Control Mode
-            if neg_pressure_countdown == 0 {
-                match notemap.get_name(&note) {
-                    Some("Low Bb") => {
-                        mode = Mode::Control;
-                        beep(&synth, 71, 50);
-                        info!("Enter Control Mode");
-                    }
-                    Some("Low B") => {
-                        mode = Mode::Transpose;
-                        beep(&synth, 71, 50);
-                        thread::sleep(Duration::from_millis(20));
-                        beep(&synth, 75, 50);
-                        info!("Enter Transpose Mode");
-                    }
-                    _ => {}
-                }
+        match instrument.take_action() {
+            Some(Action::Calibrate) => {
+                let calibration = Calibrator::new(timing);
+                println!("{}", calibration.phase().prompt());
+                calibrator = Some(calibration);
             }
-        } else {
-            if log_enabled!(Level::Debug) {
-                keyscan::debug_print(keys);
+            // Recording goes to the file of the current notemap
+            Some(Action::SwitchProfile(_)) if instrument.is_recording() => {
+                warn!("Cannot switch notemap profiles while recording");
+                alarm.raise();
             }
+            Some(Action::SwitchProfile(step)) => {
+                loader.request(&profile, step);
+            }
+            None => {}
+        }
+        if let Some(loaded) = loader.poll() {
+            switch_profile(
+                instrument,
+                &mut profile,
+                loaded,
+                sink,
+                statefile.as_deref_mut(),
+            );
         }
+
         #[cfg(feature = "instrumentation")]
         busy_pin.set_low();
     }
@@ -4,8 +4,14 @@ use std::error::Error;
 
 use log::info;
 
+use crate::sink::SoundSink;
+
+const MIDI_CC_BREATH: i32 = 2;
+
 pub struct MidiOut {
     conn_out: MidiOutputConnection,
+    // Beeps and alarms are for the player, not for whatever listens to MIDI
+    feedback: bool,
 }
 
 impl MidiOut {
@@ -35,7 +41,10 @@ impl MidiOut {
             }
         };
         let conn_out = midi_out.connect(out_port, "haxophone")?;
-        Ok(MidiOut { conn_out })
+        Ok(MidiOut {
+            conn_out,
+            feedback: false,
+        })
     }
 
     pub fn noteon(&mut self, note: i32, vel: i32) {
@@ -50,6 +59,49 @@ impl MidiOut {
         const NOTE_OFF_MSG: u8 = 0x80;
         let _ = self.conn_out.send(&[NOTE_OFF_MSG, note as u8, 0u8]);
     }
+    pub fn program_change(&mut self, prog: i32) {
+        const PROGRAM_CHANGE_MSG: u8 = 0xC0;
+        let _ = self.conn_out.send(&[PROGRAM_CHANGE_MSG, prog as u8]);
+    }
+    pub fn pitch_bend(&mut self, val: i32) {
+        const PITCH_BEND_MSG: u8 = 0xE0;
+        // 14-bit value, least significant 7 bits first
+        let lsb = (val & 0x7F) as u8;
+        let msb = ((val >> 7) & 0x7F) as u8;
+        let _ = self.conn_out.send(&[PITCH_BEND_MSG, lsb, msb]);
+    }
+}
+
+impl SoundSink for MidiOut {
+    fn noteon(&mut self, note: i32, vel: i32) {
+        if !self.feedback {
+            MidiOut::noteon(self, note, vel);
+        }
+    }
+    fn noteoff(&mut self, note: i32) {
+        if !self.feedback {
+            MidiOut::noteoff(self, note);
+        }
+    }
+    fn cc(&mut self, ctrl: i32, value: i32) {
+        if !self.feedback {
+            MidiOut::cc(self, ctrl, value);
+        }
+    }
+    fn breath(&mut self, value: i32) {
+        if !self.feedback {
+            MidiOut::cc(self, MIDI_CC_BREATH, value);
+        }
+    }
+    fn program_change(&mut self, prog: i32) {
+        MidiOut::program_change(self, prog);
+    }
+    fn pitch_bend(&mut self, value: i32) {
+        MidiOut::pitch_bend(self, value);
+    }
+    fn feedback(&mut self, on: bool) {
+        self.feedback = on;
+    }
 }
 
 #[cfg(test)]
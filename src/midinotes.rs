@@ -44,3 +44,12 @@ pub fn get_name(value: i32) -> Option<&'static str> {
     }
     None
 }
+
+pub fn get_value(name: &str) -> Option<i32> {
+    for &n in NOTES {
+        if name.eq_ignore_ascii_case(n.0) {
+            return Some(n.1);
+        }
+    }
+    None
+}
@@ -1,51 +1,643 @@
 extern crate serde_json;
 
 use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::thread;
 use std::time::Duration;
 
 use log::warn;
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use super::midinotes;
+use crate::config::{MatrixConfig, ThresholdsConfig};
+use crate::keys::{Key, Keys};
+use crate::keyscan::KeyScan;
+use crate::rules::{self, Modifier, Rule};
+
+/// Notemap file formats.  Legacy files are JSON objects from key bitmap to
+/// MIDI note.  Version 2 files are TOML, with keys and notes by name:
+///
+///   version = 2
+///
+///   [[fingering]]
+///   note = "Low A"
+///   keys = ["b", "a"]
+///
+/// Version 2 files can also hold fingering rules, see the `rules` module:
+///
+///   [[rule]]
+///   note = "Low G"
+///   keys = ["b", "a", "g"]
+///   octave = true
+///   modifiers = [{ keys = ["g#"], offset = 1 }]
+///   ignore = ["bis"]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Legacy,
+    V2,
+}
+
+impl Format {
+    /// Format for writing a file: version 2 for .toml files, legacy otherwise.
+    pub fn for_file(filename: &str) -> Self {
+        if filename.ends_with(".toml") {
+            Format::V2
+        } else {
+            Format::Legacy
+        }
+    }
+
+    // Format of existing file contents
+    fn detect(contents: &str) -> Self {
+        if contents.trim_start().starts_with('{') {
+            Format::Legacy
+        } else {
+            Format::V2
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotemapFile {
+    version: u32,
+    #[serde(default, rename = "rule", skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<RuleTable>,
+    #[serde(default, rename = "fingering", skip_serializing_if = "Vec::is_empty")]
+    fingerings: Vec<Fingering>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fingering {
+    note: Note,
+    keys: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleTable {
+    note: Note,
+    keys: Vec<String>,
+    #[serde(default)]
+    octave: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ignore: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    modifiers: Vec<ModifierTable>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModifierTable {
+    keys: Vec<String>,
+    offset: i32,
+}
+
+// Notes are written by name when they have one
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Note {
+    Name(String),
+    Number(i32),
+}
+
+/// How bad a notemap problem is.  Errors make the notemap unusable, warnings
+/// point at fingerings that will never play or notes that cannot be played.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a notemap file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Issue {
+    pub severity: Severity,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Issue {
+    fn error(line: Option<usize>, message: String) -> Self {
+        Issue {
+            severity: Severity::Error,
+            line,
+            message,
+        }
+    }
+
+    fn warning(line: Option<usize>, message: String) -> Self {
+        Issue {
+            severity: Severity::Warning,
+            line,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Error => f.write_str("error: ")?,
+            Severity::Warning => f.write_str("warning: ")?,
+        }
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+// A fingering as read from a file
+struct Entry {
+    line: Option<usize>,
+    keys: u32,
+    note: i32,
+    // For fingerings of rules, the number of don't-care keys pressed.  Plain
+    // fingerings sort first, as they win over rules.
+    rule: Option<u32>,
+}
+
+// Entries of a legacy file in file order, duplicates included
+struct LegacyEntries(Vec<(String, i32)>);
+
+impl<'de> Deserialize<'de> for LegacyEntries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = LegacyEntries;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map from key bitmaps to notes")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(LegacyEntries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+// Line number of a byte offset
+fn line_at(contents: &str, offset: usize) -> usize {
+    contents[..offset].matches('\n').count() + 1
+}
+
+// Legacy fingerings are keyed by the key bitmap, either as a number or as key
+// names such as "b+a+g".
+fn read_legacy(contents: &str, issues: &mut Vec<Issue>) -> Vec<Entry> {
+    let file: LegacyEntries = match serde_json::from_str(contents) {
+        Ok(file) => file,
+        Err(e) => {
+            issues.push(Issue::error(None, e.to_string()));
+            return Vec::new();
+        }
+    };
+    let mut entries = Vec::new();
+    // Entries are found in the text in order, to tell their lines
+    let mut offset = 0;
+    for (keys, note) in file.0 {
+        let line = match contents[offset..].find(&format!("\"{}\"", keys)) {
+            Some(found) => {
+                offset += found + keys.len() + 2;
+                Some(line_at(contents, offset))
+            }
+            None => None,
+        };
+        match keys.parse::<Keys>() {
+            Ok(keys) => entries.push(Entry {
+                line,
+                keys: keys.0,
+                note,
+                rule: None,
+            }),
+            Err(e) => issues.push(Issue::error(line, format!("fingering '{}': {}", keys, e))),
+        }
+    }
+    entries
+}
+
+// Bitmap of a list of key names
+fn keys_of(names: &[String], line: Option<usize>, issues: &mut Vec<Issue>) -> u32 {
+    let mut keys = 0;
+    for name in names.iter() {
+        match name.parse::<Keys>() {
+            Ok(key) => keys |= key.0,
+            Err(e) => issues.push(Issue::error(line, e.to_string())),
+        }
+    }
+    keys
+}
+
+fn note_of(note: Note, line: Option<usize>, issues: &mut Vec<Issue>) -> Option<i32> {
+    match note {
+        Note::Name(name) => {
+            let note = midinotes::get_value(&name);
+            if note.is_none() {
+                issues.push(Issue::error(line, format!("unknown note '{}'", name)));
+            }
+            note
+        }
+        Note::Number(note) => Some(note),
+    }
+}
+
+fn note_for(note: i32) -> Note {
+    match midinotes::get_name(note) {
+        Some(name) => Note::Name(name.to_string()),
+        None => Note::Number(note),
+    }
+}
+
+// Lines where the `count` tables of an array start.  Only tables written with
+// their own header are found, so when some are inline tables, e.g.
+// `fingering = [{ ... }]`, none get a line rather than the wrong one.
+fn table_lines(contents: &str, header: &str, count: usize) -> Vec<usize> {
+    let lines: Vec<usize> = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, text)| text.trim_start().starts_with(header))
+        .map(|(i, _)| i + 1)
+        .collect();
+    if lines.len() == count {
+        lines
+    } else {
+        Vec::new()
+    }
+}
+
+// Rules come first and are expanded to their fingerings
+fn read_v2(contents: &str, issues: &mut Vec<Issue>) -> (Vec<Rule>, Vec<Entry>) {
+    let file: NotemapFile = match toml::from_str(contents) {
+        Ok(file) => file,
+        Err(e) => {
+            issues.push(Issue::error(None, e.to_string()));
+            return (Vec::new(), Vec::new());
+        }
+    };
+    if file.version != 2 {
+        let message = format!("unsupported notemap version {}", file.version);
+        issues.push(Issue::error(None, message));
+        return (Vec::new(), Vec::new());
+    }
+    let mut rules = Vec::new();
+    let mut entries = Vec::new();
+    let lines = table_lines(contents, "[[rule]]", file.rules.len());
+    for (i, table) in file.rules.into_iter().enumerate() {
+        let line = lines.get(i).copied();
+        let rule = Rule {
+            keys: keys_of(&table.keys, line, issues),
+            octave: table.octave,
+            modifiers: table
+                .modifiers
+                .iter()
+                .map(|modifier| Modifier {
+                    keys: keys_of(&modifier.keys, line, issues),
+                    offset: modifier.offset,
+                })
+                .collect(),
+            ignore: keys_of(&table.ignore, line, issues),
+            note: match note_of(table.note, line, issues) {
+                Some(note) => note,
+                None => continue,
+            },
+        };
+        match rule.expand() {
+            Ok(variants) => entries.extend(variants.into_iter().map(|variant| Entry {
+                line,
+                keys: variant.keys,
+                note: variant.note,
+                rule: Some(variant.ignored),
+            })),
+            Err(e) => issues.push(Issue::error(line, e)),
+        }
+        rules.push(rule);
+    }
+    let lines = table_lines(contents, "[[fingering]]", file.fingerings.len());
+    for (i, fingering) in file.fingerings.into_iter().enumerate() {
+        let line = lines.get(i).copied();
+        let keys = keys_of(&fingering.keys, line, issues);
+        if let Some(note) = note_of(fingering.note, line, issues) {
+            entries.push(Entry {
+                line,
+                keys,
+                note,
+                rule: None,
+            });
+        }
+    }
+    (rules, entries)
+}
+
+// Read every fingering of a file in either format.  Problems that make the
+// file unusable are reported as errors.
+fn read(contents: &str) -> (Format, Vec<Rule>, Vec<Entry>, Vec<Issue>) {
+    let format = Format::detect(contents);
+    let mut issues = Vec::new();
+    let (rules, mut entries) = match format {
+        Format::Legacy => (Vec::new(), read_legacy(contents, &mut issues)),
+        Format::V2 => read_v2(contents, &mut issues),
+    };
+    // Rules give way to plain fingerings, and fingerings with don't-care keys
+    // pressed to more specific ones
+    let mut best: BTreeMap<u32, Option<u32>> = BTreeMap::new();
+    for entry in entries.iter() {
+        let best = best.entry(entry.keys).or_insert(entry.rule);
+        *best = entry.rule.min(*best);
+    }
+    entries.retain(|entry| entry.rule == best[&entry.keys]);
+    // The same fingering twice is harmless if it plays the same note
+    let mut seen: BTreeMap<u32, i32> = BTreeMap::new();
+    for entry in entries.iter() {
+        match seen.insert(entry.keys, entry.note) {
+            Some(note) if note != entry.note => issues.push(Issue::error(
+                entry.line,
+                format!(
+                    "fingering {} plays both {} and {}",
+                    Keys(entry.keys),
+                    note,
+                    entry.note
+                ),
+            )),
+            Some(_) if entry.rule.is_none() => issues.push(Issue::warning(
+                entry.line,
+                format!("fingering {} is listed twice", Keys(entry.keys)),
+            )),
+            _ => {}
+        }
+    }
+    sort(&mut issues);
+    (format, rules, entries, issues)
+}
+
+// Errors first, then by line, then problems of the file as a whole
+fn sort(issues: &mut [Issue]) {
+    issues.sort_by_key(|issue| {
+        (
+            issue.severity != Severity::Error,
+            issue.line.is_none(),
+            issue.line,
+        )
+    });
+}
+
+// Problems that do not stop the notemap from working
+fn lint(entries: &[Entry], matrix: &MatrixConfig) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    // A rule gives many fingerings with the same problem
+    let mut warn = |line, message| {
+        let issue = Issue::warning(line, message);
+        if !issues.contains(&issue) {
+            issues.push(issue);
+        }
+    };
+    let positions = matrix.rows.len() * matrix.cols.len();
+    // Key names only describe the wiring of the Haxophone HAT
+    let default = MatrixConfig::default();
+    let named = matrix.rows == default.rows && matrix.cols == default.cols;
+    for entry in entries.iter() {
+        if midinotes::get_name(entry.note).is_none() {
+            let message = format!("note {} is out of the saxophone range", entry.note);
+            warn(entry.line, message);
+        }
+        for bit in (0..32).filter(|bit| entry.keys & (1 << bit) != 0) {
+            let message = if bit as usize >= positions {
+                format!(
+                    "bit{} is outside the {}x{} key matrix",
+                    bit,
+                    matrix.rows.len(),
+                    matrix.cols.len()
+                )
+            } else if named && Key::from_bit(bit).is_none() {
+                format!("there is no key at bit{} of the key matrix", bit)
+            } else {
+                continue;
+            };
+            warn(entry.line, message);
+        }
+    }
+    for (name, note) in midinotes::NOTES.iter() {
+        if !entries.iter().any(|entry| entry.note == *note) {
+            warn(None, format!("no fingering for {}", name));
+        }
+    }
+    issues
+}
+
+/// Check notemap file contents and return every problem found.
+pub fn check(contents: &str, matrix: &MatrixConfig) -> Vec<Issue> {
+    let (_, _, entries, mut issues) = read(contents);
+    issues.extend(lint(&entries, matrix));
+    sort(&mut issues);
+    issues
+}
+
+// The fingerings of a file, or its errors
+type Parsed = (BTreeMap<u32, i32>, Vec<Rule>, Format);
+
+fn parse(contents: &str) -> Result<Parsed, Box<dyn Error>> {
+    let (format, rules, entries, issues) = read(contents);
+    errors(&issues)?;
+    let notemap = entries
+        .iter()
+        .map(|entry| (entry.keys, entry.note))
+        .collect();
+    Ok((notemap, rules, format))
+}
+
+// All the errors among the issues, one per line
+fn errors(issues: &[Issue]) -> Result<(), Box<dyn Error>> {
+    let errors: Vec<String> = issues
+        .iter()
+        .filter(|issue| issue.severity == Severity::Error)
+        .map(|issue| issue.to_string())
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; ").into())
+    }
+}
+
+// Legacy files keep numeric keys, so older versions can still read them
+fn to_legacy(notemap: &BTreeMap<u32, i32>) -> String {
+    serde_json::to_string_pretty(notemap).unwrap()
+}
+
+// The rules that give no fingering missing from the notemap, and what they
+// compile to.  Fingerings the rules play differently are listed over them, but
+// a removed fingering can only stay removed by dropping its rules.
+fn kept_rules(notemap: &BTreeMap<u32, i32>, rules: &[Rule]) -> (Vec<Rule>, BTreeMap<u32, i32>) {
+    let mut rules = rules.to_vec();
+    loop {
+        let compiled = match rules::compile(&rules) {
+            Ok(compiled) => compiled,
+            Err(_) => return (Vec::new(), BTreeMap::new()),
+        };
+        let missing: Vec<u32> = compiled
+            .keys()
+            .copied()
+            .filter(|keys| !notemap.contains_key(keys))
+            .collect();
+        if missing.is_empty() {
+            return (rules, compiled);
+        }
+        // Dropping a rule can uncover fingerings of other rules
+        rules.retain(|rule| match rule.expand() {
+            Ok(variants) => !variants
+                .iter()
+                .any(|variant| missing.contains(&variant.keys)),
+            Err(_) => false,
+        });
+    }
+}
+
+// Rules are kept where they can be, and only the fingerings they do not give
+// are listed, by note, lowest first
+fn to_v2(notemap: &BTreeMap<u32, i32>, rules: &[Rule]) -> String {
+    let (rules, compiled) = kept_rules(notemap, rules);
+    let mut fingerings: Vec<(u32, i32)> = notemap
+        .iter()
+        .filter(|(keys, note)| compiled.get(keys) != Some(note))
+        .map(|(k, n)| (*k, *n))
+        .collect();
+    fingerings.sort_by_key(|(keys, note)| (*note, *keys));
+    let file = NotemapFile {
+        version: 2,
+        rules: rules
+            .iter()
+            .map(|rule| RuleTable {
+                note: note_for(rule.note),
+                keys: Keys(rule.keys).into(),
+                octave: rule.octave,
+                ignore: Keys(rule.ignore).into(),
+                modifiers: rule
+                    .modifiers
+                    .iter()
+                    .map(|modifier| ModifierTable {
+                        keys: Keys(modifier.keys).into(),
+                        offset: modifier.offset,
+                    })
+                    .collect(),
+            })
+            .collect(),
+        fingerings: fingerings
+            .into_iter()
+            .map(|(keys, note)| Fingering {
+                note: note_for(note),
+                keys: Keys(keys).into(),
+            })
+            .collect(),
+    };
+    toml::to_string(&file).expect("Notemap is always serializable")
+}
 
 pub struct NoteMap {
     recording: bool,
     recording_index: usize,
-    last_keys: u32,
-    last_recorded: u32,
+    // Keys changed since the last fingering was recorded
+    unrecorded: bool,
     record_next: bool,
     filename: String,
+    format: Format,
+    // Rules of a version 2 file, kept for saving it
+    rules: Vec<Rule>,
     notemap: BTreeMap<u32, i32>,
     pub transpose: i32,
 }
 
 impl NoteMap {
-    pub fn generate(notemapfile: &str, transpose: i32) -> Self {
-        let mapfile = fs::read_to_string(notemapfile);
-        let mapfile = match mapfile {
+    /// Load the notemap to play with.  A missing file gives a blank notemap,
+    /// to record into.  Problems that do not stop the notemap from working
+    /// are logged as warnings.
+    pub fn generate(
+        notemapfile: &str,
+        transpose: i32,
+        matrix: &MatrixConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let contents = match fs::read_to_string(notemapfile) {
             Ok(contents) => contents,
             Err(_error) => {
                 warn!("Failed to load {}, creating a blank notemap.", notemapfile);
-                String::from("{}")
+                return Ok(NoteMap {
+                    filename: String::from(notemapfile),
+                    format: Format::for_file(notemapfile),
+                    ..NoteMap::from_map(BTreeMap::new(), transpose)
+                });
             }
         };
-        let notemap: BTreeMap<u32, i32> = serde_json::from_str(&mapfile).unwrap();
+        let (format, rules, entries, issues) = read(&contents);
+        errors(&issues).map_err(|e| format!("{}: {}", notemapfile, e))?;
+        for issue in issues.iter().chain(lint(&entries, matrix).iter()) {
+            warn!("{}: {}", notemapfile, issue);
+        }
+        let notemap = entries
+            .iter()
+            .map(|entry| (entry.keys, entry.note))
+            .collect();
+        Ok(NoteMap {
+            filename: String::from(notemapfile),
+            format,
+            rules,
+            ..NoteMap::from_map(notemap, transpose)
+        })
+    }
+
+    /// Load a notemap file, in either format, failing if it cannot be read.
+    pub fn load(notemapfile: &str, transpose: i32) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(notemapfile)
+            .map_err(|e| format!("Failed to read {}: {}", notemapfile, e))?;
+        let (notemap, rules, format) =
+            parse(&contents).map_err(|e| format!("{}: {}", notemapfile, e))?;
+        Ok(NoteMap {
+            filename: String::from(notemapfile),
+            format,
+            rules,
+            ..NoteMap::from_map(notemap, transpose)
+        })
+    }
+
+    /// Build a notemap from key bitmaps to concert pitch notes, not backed by
+    /// any file.
+    pub fn from_map(notemap: BTreeMap<u32, i32>, transpose: i32) -> Self {
         NoteMap {
             recording: false,
             recording_index: 0,
-            last_keys: 0,
-            last_recorded: 0,
+            unrecorded: false,
             record_next: false,
-            filename: String::from(notemapfile),
+            filename: String::new(),
+            format: Format::Legacy,
+            rules: Vec::new(),
             notemap,
             transpose,
         }
     }
 
     pub fn save(&self) {
-        let notemap_json = serde_json::to_string_pretty(&self.notemap).unwrap();
-        fs::write(&self.filename, notemap_json).expect("Unable to write file");
+        self.save_as(&self.filename, self.format)
+            .expect("Unable to write file");
+    }
+
+    /// Write the notemap to a file in the given format.
+    pub fn save_as(&self, notemapfile: &str, format: Format) -> Result<(), Box<dyn Error>> {
+        let contents = match format {
+            Format::Legacy => to_legacy(&self.notemap),
+            Format::V2 => to_v2(&self.notemap, &self.rules),
+        };
+        fs::write(notemapfile, contents)
+            .map_err(|e| format!("Failed to write {}: {}", notemapfile, e))?;
+        Ok(())
     }
 
     pub fn get(&self, key: &u32) -> std::option::Option<i32> {
@@ -53,6 +645,11 @@ impl NoteMap {
         self.notemap.get(key).map(|v| v + self.transpose)
     }
 
+    /// Key bitmaps of all the fingerings.
+    pub fn fingerings(&self) -> impl Iterator<Item = u32> + '_ {
+        self.notemap.keys().copied()
+    }
+
     pub fn get_untransposed(&self, key: &u32) -> std::option::Option<i32> {
         // Note in concert pitch. 
         self.notemap.get(key).copied()
@@ -71,13 +668,18 @@ impl NoteMap {
         self.recording
     }
 
-    pub fn record(&mut self, keys: u32, pressure: i32) -> () {
-        if pressure > 10 && self.last_recorded != keys {
+    pub fn record(&mut self, scan: &KeyScan, pressure: i32, thresholds: &ThresholdsConfig) -> () {
+        let keys = scan.keys;
+        let changed = !scan.events.is_empty();
+        if changed {
+            self.unrecorded = true;
+        }
+        if pressure > thresholds.blow && self.unrecorded {
             self.insert(keys, midinotes::NOTES[self.recording_index].1);
-            self.last_recorded = keys;
+            self.unrecorded = false;
             println!(
                 "Keymap {} recorded for {}",
-                keys,
+                Keys(keys),
                 midinotes::NOTES[self.recording_index].0
             );
             self.save();
@@ -99,7 +701,7 @@ impl NoteMap {
             }
         }
 
-        if pressure < -10 {
+        if pressure < thresholds.draw {
             if self.recording_index > 0 && keys > 0 {
                 self.recording_index -= 1;
                 println!("Back to {}", midinotes::NOTES[self.recording_index].0);
@@ -110,15 +712,12 @@ impl NoteMap {
             thread::sleep(Duration::from_millis(1001));
         }
 
-        if keys != self.last_keys {
-            if pressure < 10 && pressure > -10 {
-                println!(
-                    "Blow to record this keymap ({}) for {}",
-                    keys,
-                    midinotes::NOTES[self.recording_index].0
-                );
-            }
-            self.last_keys = keys;
+        if changed && pressure < thresholds.blow && pressure > thresholds.draw {
+            println!(
+                "Blow to record this keymap ({}) for {}",
+                Keys(keys),
+                midinotes::NOTES[self.recording_index].0
+            );
         }
     }
 
@@ -140,14 +739,184 @@ mod tests {
     #[test]
     fn update() {
         const TMP_NOTEMAP: &str = "/tmp/notemap.json";
-        let mut notemap = NoteMap::generate(TMP_NOTEMAP, -2);
+        let matrix = MatrixConfig::default();
+        let mut notemap = NoteMap::generate(TMP_NOTEMAP, -2, &matrix).unwrap();
         notemap.insert(1234567, 66);
         notemap.save();
-        let notemap2 = NoteMap::generate(TMP_NOTEMAP, -2);
+        let notemap2 = NoteMap::generate(TMP_NOTEMAP, -2, &matrix).unwrap();
         assert_eq!(notemap2.get(&1234567), Some(66i32));
         notemap.remove(&1234567);
         notemap.save();
-        let notemap2 = NoteMap::generate(TMP_NOTEMAP, -2);
+        let notemap2 = NoteMap::generate(TMP_NOTEMAP, -2, &matrix).unwrap();
         assert_eq!(notemap2.get(&1234567), None);
     }
+
+    #[test]
+    fn key_names() -> Result<(), Box<dyn Error>> {
+        let notemap = parse(r#"{"b+a+g": 67, "9344": 68, "0x480": 69, "b": 71}"#);
+        assert!(notemap.is_err());
+        let (notemap, _, _) = parse(r#"{"b+a+g": 67, "0x480": 69, "128": 71}"#)?;
+        assert_eq!(notemap.get(&0x2480), Some(&67));
+        assert_eq!(notemap.get(&0x480), Some(&69));
+        assert_eq!(notemap.get(&0x80), Some(&71));
+        assert!(parse(r#"{"b+x": 67}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn v2() -> Result<(), Box<dyn Error>> {
+        let (notemap, _, format) = parse(
+            r#"version = 2
+
+            [[fingering]]
+            note = "Low A"
+            keys = ["b", "a"]
+
+            [[fingering]]
+            note = "Mid Bb"
+            keys = ["b", "bis"]
+
+            [[fingering]]
+            note = 100
+            keys = ["octave", "bit11"]"#,
+        )?;
+        assert_eq!(format, Format::V2);
+        assert_eq!(notemap.get(&0x480), Some(&69));
+        assert_eq!(notemap.get(&0x90), Some(&70));
+        assert_eq!(notemap.get(&0x801), Some(&100));
+        assert!(parse("version = 3").is_err());
+        assert!(parse("version = 2\n[[fingering]]\nnote = \"Low H\"\nkeys = []").is_err());
+
+        // Both formats convert to each other without loss
+        let legacy = fs::read_to_string("notemap.json")?;
+        let (notemap, _, format) = parse(&legacy)?;
+        assert_eq!(format, Format::Legacy);
+        let v2 = to_v2(&notemap, &[]);
+        assert!(v2.contains("note = \"Low Bb\"\nkeys = [\"b\", \"a\", \"g\", \"f\", \"low-c\""));
+        assert_eq!(parse(&v2)?, (notemap.clone(), vec![], Format::V2));
+        assert_eq!(
+            parse(&to_legacy(&notemap))?,
+            (notemap, vec![], Format::Legacy)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rules() -> Result<(), Box<dyn Error>> {
+        let contents = r#"version = 2
+
+            [[rule]]
+            note = "Low G"
+            keys = ["b", "a", "g"]
+            octave = true
+            ignore = ["bis"]
+            modifiers = [{ keys = ["g#"], offset = 1 }]
+
+            [[fingering]]
+            note = "Low Ab"
+            keys = ["bis", "b", "a", "g"]"#;
+        let (notemap, rules, _) = parse(contents)?;
+        assert_eq!(notemap.len(), 8);
+        assert_eq!(notemap.get(&0x2480), Some(&67));
+        assert_eq!(notemap.get(&0x2481), Some(&79));
+        assert_eq!(notemap.get(&0x6481), Some(&80));
+        // The fingering is more specific than the rule
+        assert_eq!(notemap.get(&0x2490), Some(&68));
+
+        // Saving keeps the rules, and lists only what they do not give
+        let v2 = to_v2(&notemap, &rules);
+        assert_eq!(v2.matches("[[rule]]").count(), 1);
+        assert_eq!(v2.matches("[[fingering]]").count(), 1);
+        assert_eq!(parse(&v2)?, (notemap.clone(), rules.clone(), Format::V2));
+
+        // Recording over a fingering of the rule, and removing one
+        let mut recorded = notemap.clone();
+        recorded.insert(0x2480, 66);
+        let (reloaded, reloaded_rules, _) = parse(&to_v2(&recorded, &rules))?;
+        assert_eq!(reloaded, recorded);
+        assert_eq!(reloaded_rules, rules);
+        recorded.remove(&0x6481);
+        let v2 = to_v2(&recorded, &rules);
+        assert_eq!(v2.matches("[[rule]]").count(), 0);
+        assert_eq!(parse(&v2)?, (recorded, vec![], Format::V2));
+
+        let issues = check(
+            "version = 2\n[[rule]]\nnote = 67\nkeys = [\"octave\"]\noctave = true",
+            &MatrixConfig::default(),
+        );
+        assert_eq!(
+            issues[0].to_string(),
+            "error: line 2: the octave key is part of the fingering"
+        );
+
+        // The rule chart plays every fingering of the legacy notemap the same
+        let (legacy, _, _) = parse(&fs::read_to_string("notemap.json")?)?;
+        let (chart, rules, _) = parse(&fs::read_to_string("profiles/standard.toml")?)?;
+        assert_eq!(rules.len(), 16);
+        assert_eq!(rules::compile(&rules)?, chart);
+        for (keys, note) in legacy.iter() {
+            assert_eq!(chart.get(keys), Some(note), "fingering {}", Keys(*keys));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_report() {
+        let matrix = MatrixConfig::default();
+        let issues = check(&fs::read_to_string("notemap.json").unwrap(), &matrix);
+        assert_eq!(issues, vec![]);
+
+        let contents = r#"{
+            "b+a": 69,
+            "0x480": 70,
+            "b": 71,
+            "128": 71,
+            "bit11+b": 71,
+            "bit30": 100,
+            "b+h": 72
+        }"#;
+        let issues: Vec<String> = check(contents, &matrix)
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect();
+        assert_eq!(
+            issues[..7],
+            [
+                "error: line 3: fingering b+a plays both 69 and 70",
+                "error: line 8: fingering 'b+h': unknown key 'h'",
+                "warning: line 5: fingering b is listed twice",
+                "warning: line 6: there is no key at bit11 of the key matrix",
+                "warning: line 7: note 100 is out of the saxophone range",
+                "warning: line 7: bit30 is outside the 8x3 key matrix",
+                "warning: no fingering for Low Bb",
+            ]
+        );
+        // Low Bb and 29 more notes without a fingering
+        assert_eq!(issues.len(), 6 + midinotes::NOTES.len() - 3);
+
+        // Other wiring, other keys
+        let custom = MatrixConfig {
+            rows: vec![4, 5, 6, 7, 8, 9, 10, 11],
+            ..MatrixConfig::default()
+        };
+        let issues = check(contents, &custom);
+        assert!(!issues.iter().any(|issue| issue.message.contains("bit11")));
+
+        // Inline tables have no header to find their line by
+        let contents = r#"version = 2
+            fingering = [{ note = "Low H", keys = ["b"] }]
+
+            [[rule]]
+            note = "Low X"
+            keys = ["b"]"#;
+        let lines: Vec<Option<usize>> = check(contents, &matrix)[..2]
+            .iter()
+            .map(|issue| issue.line)
+            .collect();
+        assert_eq!(lines, [Some(4), None]);
+
+        let issue = &check("version = 2\nfingering = 3", &matrix)[0];
+        assert_eq!(issue.severity, Severity::Error);
+        assert_eq!(issue.line, None);
+    }
 }
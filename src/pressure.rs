@@ -1,20 +1,146 @@
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::error::Error;
+use std::f64::consts::PI;
+use std::fs;
+use std::thread;
+use std::time::Duration;
 
-use log::{debug, error /* info, warn */};
+use log::{debug, error, info, warn};
 
 use rppal::i2c::I2c;
 
+use crate::calibrate::Calibration;
+use crate::config::{PressureConfig, TimingConfig};
+use crate::curve::Response;
+use crate::filter::Filters;
+
 // Pressure sensor I2C address
 const ADDR_PRESSURE_SENSOR: u16 = 0x4D;
 
+// The resting baseline is averaged over this many samples, 1ms apart
+const BASELINE_SAMPLES: i32 = 32;
+
+// Readings must be stable for this long before the baseline starts following
+// them
+const DRIFT_SETTLE_MS: u32 = 500;
+// Time constant of the baseline following stable readings
+const DRIFT_TIME_CONSTANT_MS: u32 = 2000;
+
+/// A source of breath pressure readings.  Positive values (blowing) are
+/// scaled to the 0-127 MIDI range, negative values (drawing) are reported as
+/// is so they can be used for mode changes.
+pub trait BreathSensor {
+    fn read(&mut self) -> Result<i32, Box<dyn Error>>;
+
+    /// Unscaled reading, not relative to any baseline, for calibration.
+    /// Sensors without a raw scale return the same as `read`.
+    fn read_raw(&mut self) -> Result<i32, Box<dyn Error>> {
+        self.read()
+    }
+
+    /// Scale readings according to a new calibration.
+    fn calibrate(&mut self, _calibration: &Calibration) {}
+
+    /// Called after every read with whether a note is sounding, so the sensor
+    /// can follow baseline drift while the player is not blowing.
+    fn track_baseline(&mut self, _sounding: bool) {}
+}
+
+/// Follows slow changes of the zero pressure reading, from temperature or
+/// condensation, using readings taken while the player is not blowing.
+pub struct BaselineTracker {
+    initial: i32,
+    baseline: f32,
+    band: i32,
+    warning: i32,
+    low: i32,
+    high: i32,
+    stable_reads: u32,
+    settle_reads: u32,
+    // Fraction of the offset the baseline catches up on every stable read
+    rate: f32,
+    drifted: bool,
+}
+
+impl BaselineTracker {
+    pub fn new(baseline: i32, band: i32, warning: i32, timing: &TimingConfig) -> Self {
+        BaselineTracker {
+            initial: baseline,
+            baseline: baseline as f32,
+            band,
+            warning,
+            low: 0,
+            high: 0,
+            stable_reads: 0,
+            settle_reads: max(1, timing.ms_to_ticks(DRIFT_SETTLE_MS)),
+            rate: 1.0 / max(1, timing.ms_to_ticks(DRIFT_TIME_CONSTANT_MS)) as f32,
+            drifted: false,
+        }
+    }
+
+    pub fn baseline(&self) -> i32 {
+        self.baseline.round() as i32
+    }
+
+    /// Feed a raw reading taken while no note was sounding.  Readings away
+    /// from the baseline are a sustained draw or blow, not drift.
+    pub fn update(&mut self, raw: i32) {
+        if (raw - self.baseline()).abs() > self.band {
+            self.stable_reads = 0;
+            return;
+        }
+        if self.stable_reads == 0 || max(self.high, raw) - min(self.low, raw) > self.band {
+            self.low = raw;
+            self.high = raw;
+            self.stable_reads = 0;
+        } else {
+            self.low = min(self.low, raw);
+            self.high = max(self.high, raw);
+        }
+        self.stable_reads += 1;
+        if self.stable_reads < self.settle_reads {
+            return;
+        }
+
+        self.baseline += (raw as f32 - self.baseline) * self.rate;
+        let drift = (self.baseline() - self.initial).abs();
+        if drift > self.warning && !self.drifted {
+            warn!("Pressure baseline drifted by {} counts", drift);
+            self.drifted = true;
+        } else if drift <= self.warning / 2 && self.drifted {
+            info!("Pressure baseline back within {} counts", drift);
+            self.drifted = false;
+        }
+    }
+
+    /// Follow drift from a new baseline, e.g. a calibrated one.
+    pub fn reset(&mut self, baseline: i32) {
+        self.initial = baseline;
+        self.baseline = baseline as f32;
+        self.stable_reads = 0;
+        self.drifted = false;
+    }
+
+    /// Start over looking for a stable period, e.g. because a note sounds.
+    pub fn interrupt(&mut self) {
+        self.stable_reads = 0;
+    }
+}
+
 pub struct Pressure {
     i2c: rppal::i2c::I2c,
-    baseline: i32,
+    tracker: BaselineTracker,
+    last_raw: i32,
+    config: PressureConfig,
+    response: Response,
+    filters: Filters,
 }
 
 impl Pressure {
-    pub fn init() -> Result<Pressure, Box<dyn Error>> {
+    pub fn init(
+        config: &PressureConfig,
+        timing: &TimingConfig,
+    ) -> Result<Pressure, Box<dyn Error>> {
         debug!("I2C: Configuring bus ...");
 
         let maybe_i2c = I2c::new();
@@ -38,26 +164,37 @@ impl Pressure {
 
         debug!("I2C: slave address set to {}", ADDR_PRESSURE_SENSOR);
 
-        let baseline = Pressure::read_io(&mut i2c)?;
+        let baseline = match config.baseline {
+            Some(baseline) => baseline,
+            None => {
+                let mut sum = 0;
+                for _ in 0..BASELINE_SAMPLES {
+                    sum += Pressure::read_io(&mut i2c)?;
+                    thread::sleep(Duration::from_millis(1));
+                }
+                sum / BASELINE_SAMPLES
+            }
+        };
 
         let sensor = Pressure {
             i2c: i2c,
-            baseline: baseline,
+            tracker: BaselineTracker::new(
+                baseline,
+                config.drift_band,
+                config.drift_warning,
+                timing,
+            ),
+            last_raw: baseline,
+            config: config.clone(),
+            response: Response::new(&config.curve, config.dead_zone, config.ceiling),
+            filters: Filters::new(&config.filters),
         };
 
-        debug!("I2C: baseline set to {}", sensor.baseline);
+        debug!("I2C: baseline set to {}", sensor.tracker.baseline());
 
         Ok(sensor)
     }
 
-    pub fn read(&mut self) -> Result<i32, Box<dyn Error>> {
-        let pressure = Pressure::read_io(&mut self.i2c)?;
-        // Compress the the range returned by the sensor to 0-127 required
-        // for MIDI.  TODO:  Make this configurable
-        const PRESSURE_SCALING_FACTOR: i32 = 6;
-        Ok(min((pressure - self.baseline) / PRESSURE_SCALING_FACTOR, 127))
-    }
-
     fn read_io(i2c: &mut rppal::i2c::I2c) -> Result<i32, Box<dyn Error>> {
         let mut reg = [0u8; 2];
         let mut result;
@@ -68,6 +205,145 @@ impl Pressure {
         result = result - 2048;
         Ok(result)
     }
+
+    // Mean of `oversampling` readings taken back to back
+    fn sample(&mut self) -> Result<i32, Box<dyn Error>> {
+        let samples = max(1, self.config.oversampling) as i32;
+        let mut sum = 0;
+        for _ in 0..samples {
+            sum += Pressure::read_io(&mut self.i2c)?;
+        }
+        Ok(sum / samples)
+    }
+}
+
+impl BreathSensor for Pressure {
+    fn read(&mut self) -> Result<i32, Box<dyn Error>> {
+        self.last_raw = self.sample()?;
+        // Filter the raw counts, then compress them to the 0-127 range
+        // required for MIDI.
+        let response = &self.response;
+        Ok(self
+            .filters
+            .process(self.last_raw - self.tracker.baseline(), |raw| {
+                response.map(raw)
+            }))
+    }
+
+    fn read_raw(&mut self) -> Result<i32, Box<dyn Error>> {
+        self.sample()
+    }
+
+    fn calibrate(&mut self, calibration: &Calibration) {
+        calibration.apply(&mut self.config);
+        if let Some(baseline) = self.config.baseline {
+            self.tracker.reset(baseline);
+            self.last_raw = baseline;
+        }
+        self.response = Response::new(
+            &self.config.curve,
+            self.config.dead_zone,
+            self.config.ceiling,
+        );
+    }
+
+    fn track_baseline(&mut self, sounding: bool) {
+        if !self.config.track_drift {
+            return;
+        }
+        if sounding {
+            self.tracker.interrupt();
+        } else {
+            self.tracker.update(self.last_raw);
+        }
+    }
+}
+
+/// Synthetic pressure signals, with periods expressed in reads (ticks).
+#[derive(Clone, Debug)]
+pub enum Waveform {
+    Constant(i32),
+    Square { low: i32, high: i32, period: u32 },
+    Sine { amplitude: i32, period: u32 },
+}
+
+impl Waveform {
+    fn sample(&self, tick: usize) -> i32 {
+        match *self {
+            Waveform::Constant(value) => value,
+            Waveform::Square { low, high, period } => {
+                let period = period.max(1) as usize;
+                if tick % period < period / 2 {
+                    high
+                } else {
+                    low
+                }
+            }
+            Waveform::Sine { amplitude, period } => {
+                let phase = 2.0 * PI * tick as f64 / period.max(1) as f64;
+                (amplitude as f64 * phase.sin()).round() as i32
+            }
+        }
+    }
+}
+
+enum Playback {
+    Waveform(Waveform),
+    Trace(Vec<i32>),
+}
+
+/// Breath sensor that plays back a waveform or a recorded pressure trace
+/// instead of talking to the hardware.  Values are returned as they would be
+/// by `Pressure::read`.  A trace holds its last value once it runs out.
+pub struct SimulatedSensor {
+    playback: Playback,
+    tick: usize,
+}
+
+impl SimulatedSensor {
+    pub fn from_waveform(waveform: Waveform) -> Self {
+        SimulatedSensor {
+            playback: Playback::Waveform(waveform),
+            tick: 0,
+        }
+    }
+
+    pub fn from_trace(trace: Vec<i32>) -> Self {
+        SimulatedSensor {
+            playback: Playback::Trace(trace),
+            tick: 0,
+        }
+    }
+
+    /// Load a trace file with one pressure value per read.  Values can be
+    /// separated by any whitespace and lines starting with '#' are ignored.
+    pub fn load_trace(tracefile: &str) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(tracefile)?;
+        let mut trace = Vec::new();
+        for line in contents.lines() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            for value in line.split_whitespace() {
+                trace.push(value.parse()?);
+            }
+        }
+        Ok(SimulatedSensor::from_trace(trace))
+    }
+}
+
+impl BreathSensor for SimulatedSensor {
+    fn read(&mut self) -> Result<i32, Box<dyn Error>> {
+        let pressure = match &self.playback {
+            Playback::Waveform(waveform) => waveform.sample(self.tick),
+            Playback::Trace(trace) => {
+                let last = trace.len().saturating_sub(1);
+                trace.get(min(self.tick, last)).copied().unwrap_or(0)
+            }
+        };
+        self.tick += 1;
+        Ok(pressure)
+    }
 }
 
 #[cfg(test)]
@@ -80,16 +356,90 @@ mod tests {
 
     #[test]
     fn init() {
-        let mut _sensor = Pressure::init().expect("Failed to initialize pressure sensor");
+        let mut _sensor = Pressure::init(&PressureConfig::default(), &TimingConfig::default())
+            .expect("Failed to initialize pressure sensor");
     }
 
     #[test]
     fn read() -> Result<(), Box<dyn Error>> {
-        let mut sensor = Pressure::init().expect("Failed to initialize pressure sensor");
+        let mut sensor = Pressure::init(&PressureConfig::default(), &TimingConfig::default())
+            .expect("Failed to initialize pressure sensor");
         let _pressure = sensor.read()?;
         Ok(())
     }
 
+    #[test]
+    fn simulated_trace() -> Result<(), Box<dyn Error>> {
+        let mut sensor = SimulatedSensor::from_trace(vec![0, 40, -20]);
+        assert_eq!(sensor.read()?, 0);
+        assert_eq!(sensor.read()?, 40);
+        assert_eq!(sensor.read()?, -20);
+        // Trace holds its last value
+        assert_eq!(sensor.read()?, -20);
+        Ok(())
+    }
+
+    #[test]
+    fn simulated_waveform() -> Result<(), Box<dyn Error>> {
+        let mut square = SimulatedSensor::from_waveform(Waveform::Square {
+            low: -15,
+            high: 60,
+            period: 4,
+        });
+        let readings: Vec<i32> = (0..6).map(|_| square.read().unwrap()).collect();
+        assert_eq!(readings, vec![60, 60, -15, -15, 60, 60]);
+
+        let mut sine = SimulatedSensor::from_waveform(Waveform::Sine {
+            amplitude: 100,
+            period: 4,
+        });
+        let readings: Vec<i32> = (0..4).map(|_| sine.read().unwrap()).collect();
+        assert_eq!(readings, vec![0, 100, 0, -100]);
+        Ok(())
+    }
+
+    #[test]
+    fn baseline_tracking() {
+        // 250 reads to settle, time constant of 1000 reads
+        let timing = TimingConfig::default();
+        let settle = timing.ms_to_ticks(DRIFT_SETTLE_MS);
+        let mut tracker = BaselineTracker::new(0, 8, 10, &timing);
+        // Unstable readings are not drift
+        for i in 0..2000 {
+            tracker.update(if i % 2 == 0 { -5 } else { 5 });
+        }
+        assert_eq!(tracker.baseline(), 0);
+
+        for _ in 0..settle - 1 {
+            tracker.update(6);
+        }
+        assert_eq!(tracker.baseline(), 0);
+        // Followed step by step as it creeps away
+        for reading in [6, 12, 18].iter() {
+            for _ in 0..5000 {
+                tracker.update(*reading);
+            }
+        }
+        assert_eq!(tracker.baseline(), 18);
+
+        // Playing restarts the settling period
+        tracker.interrupt();
+        for _ in 0..settle - 1 {
+            tracker.update(20);
+        }
+        assert_eq!(tracker.baseline(), 18);
+
+        // A steady draw or blow with no note sounding is not drift
+        for _ in 0..5000 {
+            tracker.update(-30);
+            tracker.update(60);
+        }
+        for _ in 0..5000 {
+            tracker.update(60);
+        }
+        assert_eq!(tracker.baseline(), 18);
+    }
+
     /* This test is ignored by default because it expects pressure readings to change over time.
     In order to do that, you might need to blow some air into the tube.
 
@@ -100,7 +450,8 @@ mod tests {
     #[ignore]
     fn pressure_step() -> Result<(), Box<dyn Error>> {
         println!("Blow and draw air from the mouthpiece...");
-        let mut sensor = Pressure::init().expect("Failed to initialize pressure sensor");
+        let mut sensor = Pressure::init(&PressureConfig::default(), &TimingConfig::default())
+            .expect("Failed to initialize pressure sensor");
         let mut pressure_positive_detected = false;
         let mut pressure_negative_detected = false;
         for _ in 0..100 {
@@ -138,7 +489,8 @@ mod tests {
     #[ignore]
     fn read_io() -> Result<(), Box<dyn Error>> {
         println!("Blow and draw on the mouthpiece...");
-        let mut sensor = Pressure::init().expect("Failed to initialize pressure sensor");
+        let mut sensor = Pressure::init(&PressureConfig::default(), &TimingConfig::default())
+            .expect("Failed to initialize pressure sensor");
         let mut max_val: i32 = 0;
         let mut min_val: i32 = i32::MAX;
         let mut pressure_range_detected = false;
@@ -0,0 +1,221 @@
+// Named notemaps, to switch between fingering charts.  Every notemap file of
+// the profiles directory, in either format, is a profile named after the file:
+// `profiles/beginner.toml` is the "beginner" profile.  The directory is read
+// again on every switch, so profiles can be added without a restart.  Only the
+// standard chart ships; others are for the player to record or write.
+//
+// Reading and checking a profile takes far longer than a scan tick, so
+// switches load it on a thread of their own while the play loop goes on.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use log::info;
+
+use crate::config::MatrixConfig;
+use crate::notemap::NoteMap;
+
+pub struct Profiles {
+    // Sorted by name
+    profiles: Vec<(String, PathBuf)>,
+}
+
+impl Profiles {
+    /// List the profiles of a directory.  A missing directory has none.
+    pub fn scan(dir: &str) -> Self {
+        let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file() && extension(path).is_some())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        // A version 2 file wins over a legacy file of the same name
+        files.sort_by_key(|path| {
+            (
+                path.file_stem().map(|stem| stem.to_owned()),
+                extension(path),
+            )
+        });
+        let mut profiles: Vec<(String, PathBuf)> = Vec::new();
+        for path in files {
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            if profiles.last().map(|(last, _)| last) != Some(&name) {
+                profiles.push((name, path));
+            }
+        }
+        Profiles { profiles }
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Notemap file of a profile.
+    pub fn file(&self, name: &str) -> Option<String> {
+        self.profiles
+            .iter()
+            .find(|(profile, _)| profile == name)
+            .map(|(_, path)| path.to_string_lossy().into_owned())
+    }
+
+    /// The profile `step` places away from `name`, in alphabetical order and
+    /// wrapping around.  From a profile that does not exist, the first one.
+    pub fn step(&self, name: &str, step: i32) -> Option<&str> {
+        if self.profiles.is_empty() {
+            return None;
+        }
+        let count = self.profiles.len() as i32;
+        let index = match self.names().position(|profile| profile == name) {
+            Some(index) => (index as i32 + step).rem_euclid(count),
+            None => 0,
+        };
+        Some(&self.profiles[index as usize].0)
+    }
+}
+
+/// A profile loaded in the background: its name, file and notemap, or why it
+/// could not be loaded.
+pub type Loaded = Result<(String, String, NoteMap), String>;
+
+/// Loads the profiles to switch to, one at a time, away from the play loop.
+pub struct ProfileLoader {
+    dir: String,
+    matrix: MatrixConfig,
+    pending: Option<Receiver<Loaded>>,
+}
+
+impl ProfileLoader {
+    pub fn new(dir: &str, matrix: &MatrixConfig) -> Self {
+        ProfileLoader {
+            dir: String::from(dir),
+            matrix: matrix.clone(),
+            pending: None,
+        }
+    }
+
+    /// Start loading the profile `step` places away from `current`.  Returns
+    /// false if another one is still loading.
+    pub fn request(&mut self, current: &str, step: i32) -> bool {
+        if self.pending.is_some() {
+            info!("Still loading a notemap profile");
+            return false;
+        }
+        let (sender, receiver) = mpsc::channel();
+        let dir = self.dir.clone();
+        let matrix = self.matrix.clone();
+        let current = String::from(current);
+        thread::spawn(move || {
+            let _ = sender.send(load(&dir, &current, step, &matrix));
+        });
+        self.pending = Some(receiver);
+        true
+    }
+
+    /// The profile requested last, once it is loaded.
+    pub fn poll(&mut self) -> Option<Loaded> {
+        let loaded = match self.pending.as_ref()?.try_recv() {
+            Ok(loaded) => loaded,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(String::from("Profile loader failed")),
+        };
+        self.pending = None;
+        Some(loaded)
+    }
+}
+
+fn load(dir: &str, current: &str, step: i32, matrix: &MatrixConfig) -> Loaded {
+    let profiles = Profiles::scan(dir);
+    let name = match profiles.step(current, step) {
+        Some(name) => name.to_string(),
+        None => return Err(format!("No notemap profiles in {}", dir)),
+    };
+    let file = profiles.file(&name).expect("Profile was just listed");
+    let notemap = NoteMap::generate(&file, 0, matrix).map_err(|e| e.to_string())?;
+    Ok((name, file, notemap))
+}
+
+// Notemap files by extension, version 2 first
+fn extension(path: &Path) -> Option<u8> {
+    match path.extension()?.to_str()? {
+        "toml" => Some(0),
+        "json" => Some(1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_and_step() {
+        const TMP_PROFILES: &str = "/tmp/haxo-test/profiles";
+        let _ = fs::remove_dir_all(TMP_PROFILES);
+        fs::create_dir_all(TMP_PROFILES).unwrap();
+        for file in [
+            "standard.toml",
+            "ewi.json",
+            "recorder.json",
+            "recorder.toml",
+            "README",
+        ]
+        .iter()
+        {
+            fs::write(Path::new(TMP_PROFILES).join(file), "").unwrap();
+        }
+
+        let profiles = Profiles::scan(TMP_PROFILES);
+        let names: Vec<&str> = profiles.names().collect();
+        assert_eq!(names, ["ewi", "recorder", "standard"]);
+        assert_eq!(
+            profiles.file("recorder"),
+            Some(format!("{}/recorder.toml", TMP_PROFILES))
+        );
+        assert_eq!(profiles.file("README"), None);
+        assert_eq!(profiles.step("ewi", 1), Some("recorder"));
+        assert_eq!(profiles.step("ewi", -1), Some("standard"));
+        assert_eq!(profiles.step("standard", 1), Some("ewi"));
+        assert_eq!(profiles.step("gone", 1), Some("ewi"));
+
+        assert_eq!(Profiles::scan("/tmp/haxo-test/none").step("ewi", 1), None);
+    }
+
+    #[test]
+    fn loader() {
+        const TMP_PROFILES: &str = "/tmp/haxo-test/loader";
+        let _ = fs::remove_dir_all(TMP_PROFILES);
+        fs::create_dir_all(TMP_PROFILES).unwrap();
+        fs::write(
+            Path::new(TMP_PROFILES).join("alto.toml"),
+            "version = 2\n[[fingering]]\nkeys = [\"b\"]\nnote = \"Mid B\"\n",
+        )
+        .unwrap();
+        fs::write(Path::new(TMP_PROFILES).join("broken.toml"), "version = 3").unwrap();
+
+        fn wait(loader: &mut ProfileLoader) -> Loaded {
+            loop {
+                if let Some(loaded) = loader.poll() {
+                    return loaded;
+                }
+                thread::yield_now();
+            }
+        }
+
+        let mut loader = ProfileLoader::new(TMP_PROFILES, &MatrixConfig::default());
+        assert!(loader.request("", 1));
+        assert!(!loader.request("", 1));
+        let (name, _, notemap) = wait(&mut loader).unwrap();
+        assert_eq!(name, "alto");
+        assert_eq!(notemap.get(&0x80), Some(71));
+
+        assert!(loader.request("alto", 1));
+        assert!(wait(&mut loader).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+// Fingering rules, to write a notemap as a few dozen rules instead of every
+// key combination.  A rule is a base fingering and the note it plays, plus:
+//
+//   - octave: the same fingering with the octave key plays an octave higher
+//   - modifiers: keys that, added to the fingering, shift the note by a number
+//     of semitones.  Each modifier applies on its own, not together with the
+//     others.
+//   - ignore: don't-care keys, that play the same note pressed or not
+//
+// A rule stands for every combination of these.  Where two rules give the same
+// key combination, the one with fewer don't-care keys pressed wins.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::keys::{Key, Keys};
+
+// Every subset of the don't-care keys is a fingering, so their number is
+// kept small
+pub const MAX_IGNORED: u32 = 8;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rule {
+    pub note: i32,
+    pub keys: u32,
+    pub octave: bool,
+    pub modifiers: Vec<Modifier>,
+    pub ignore: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Modifier {
+    pub keys: u32,
+    pub offset: i32,
+}
+
+/// One key combination of a rule.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Variant {
+    pub keys: u32,
+    pub note: i32,
+    // Number of don't-care keys pressed
+    pub ignored: u32,
+}
+
+impl Rule {
+    /// Every key combination of the rule, or why the rule makes no sense.
+    pub fn expand(&self) -> Result<Vec<Variant>, String> {
+        let octave = Key::Octave.mask();
+        if self.octave && self.keys & octave != 0 {
+            return Err("the octave key is part of the fingering".to_string());
+        }
+        let extra = if self.octave { octave } else { 0 };
+        let overlap = (self.keys | extra) & self.ignore;
+        if overlap != 0 {
+            return Err(format!("keys {} are not don't-care keys", Keys(overlap)));
+        }
+        if self.ignore.count_ones() > MAX_IGNORED {
+            return Err(format!("more than {} don't-care keys", MAX_IGNORED));
+        }
+        for modifier in self.modifiers.iter() {
+            if modifier.keys == 0 {
+                return Err("modifier without keys".to_string());
+            }
+            let overlap = modifier.keys & (self.keys | self.ignore | extra);
+            if overlap != 0 {
+                return Err(format!(
+                    "modifier keys {} are already part of the rule",
+                    Keys(overlap)
+                ));
+            }
+        }
+
+        let base = Modifier { keys: 0, offset: 0 };
+        let mut variants = Vec::new();
+        // Every subset of the don't-care keys, all of them first
+        let mut pressed = self.ignore;
+        loop {
+            for modifier in std::iter::once(&base).chain(self.modifiers.iter()) {
+                let keys = self.keys | modifier.keys | pressed;
+                let note = self.note + modifier.offset;
+                let ignored = pressed.count_ones();
+                variants.push(Variant {
+                    keys,
+                    note,
+                    ignored,
+                });
+                if self.octave {
+                    variants.push(Variant {
+                        keys: keys | octave,
+                        note: note + 12,
+                        ignored,
+                    });
+                }
+            }
+            if pressed == 0 {
+                break;
+            }
+            pressed = (pressed - 1) & self.ignore;
+        }
+        Ok(variants)
+    }
+}
+
+/// Compile rules to the map from key bitmaps to notes used when playing.
+pub fn compile(rules: &[Rule]) -> Result<BTreeMap<u32, i32>, Box<dyn Error>> {
+    let mut fingerings: BTreeMap<u32, Variant> = BTreeMap::new();
+    for (i, rule) in rules.iter().enumerate() {
+        let variants = rule
+            .expand()
+            .map_err(|e| format!("rule {}: {}", i + 1, e))?;
+        for variant in variants {
+            let known = fingerings.entry(variant.keys).or_insert(variant);
+            if variant.ignored < known.ignored {
+                *known = variant;
+            } else if variant.ignored == known.ignored && variant.note != known.note {
+                return Err(format!(
+                    "rule {}: fingering {} plays both {} and {}",
+                    i + 1,
+                    Keys(variant.keys),
+                    known.note,
+                    variant.note
+                )
+                .into());
+            }
+        }
+    }
+    Ok(fingerings
+        .into_iter()
+        .map(|(keys, variant)| (keys, variant.note))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::keys::mask;
+
+    const LOW_D: u32 = mask(&[Key::B, Key::A, Key::G, Key::F, Key::E, Key::D]);
+
+    #[test]
+    fn expand() {
+        let rule = Rule {
+            note: 62,
+            keys: LOW_D,
+            octave: true,
+            modifiers: vec![Modifier {
+                keys: Key::LowEb.mask(),
+                offset: 1,
+            }],
+            ignore: Key::Bis.mask(),
+        };
+        let notemap = compile(std::slice::from_ref(&rule)).unwrap();
+        let octave = Key::Octave.mask();
+        let bis = Key::Bis.mask();
+        let low_eb = Key::LowEb.mask();
+        assert_eq!(notemap.len(), 8);
+        assert_eq!(notemap.get(&LOW_D), Some(&62));
+        assert_eq!(notemap.get(&(LOW_D | bis)), Some(&62));
+        assert_eq!(notemap.get(&(LOW_D | octave)), Some(&74));
+        assert_eq!(notemap.get(&(LOW_D | octave | bis | low_eb)), Some(&75));
+
+        let rule = Rule {
+            keys: LOW_D | octave,
+            ..rule
+        };
+        assert!(rule.expand().is_err());
+        let rule = Rule {
+            keys: LOW_D,
+            octave: false,
+            ignore: LOW_D,
+            ..Rule::default()
+        };
+        assert!(rule.expand().is_err());
+    }
+
+    #[test]
+    fn specific_rules_win() {
+        let low_a = mask(&[Key::B, Key::A]);
+        let rules = [
+            Rule {
+                note: 71,
+                keys: Key::B.mask(),
+                ignore: Key::A.mask() | Key::Bis.mask(),
+                ..Rule::default()
+            },
+            Rule {
+                note: 69,
+                keys: low_a,
+                ..Rule::default()
+            },
+        ];
+        let notemap = compile(&rules).unwrap();
+        assert_eq!(notemap.get(&low_a), Some(&69));
+        assert_eq!(notemap.get(&(low_a | Key::Bis.mask())), Some(&71));
+
+        // As specific as each other
+        let rules = [
+            rules[1].clone(),
+            Rule {
+                note: 70,
+                ..rules[1].clone()
+            },
+        ];
+        assert!(compile(&rules).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+// Run the instrument without hardware.  Key and pressure input come from a
+// script file and the resulting sound events are written out as text, one per
+// line, prefixed with the time in milliseconds at which they were produced.
+//
+// Script files have one step per line:
+//
+//   # time_ms  keys   pressure
+//   0          0x0    0
+//   100        0x480  40
+//   600        0x480  0
+//
+// Keys are key names joined by '+', such as "b+a", or the raw matrix bitmap in
+// decimal or 0x-prefixed hex.  Every step
+// holds until the time of the next one, and the simulation ends at the time of
+// the last step.
+
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+
+use crate::keys::Keys;
+use crate::keyscan::ScriptedScanner;
+use crate::pressure::SimulatedSensor;
+use crate::sink::{Event, SoundSink};
+
+struct Step {
+    time_ms: u32,
+    keys: u32,
+    pressure: i32,
+}
+
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+impl Script {
+    pub fn load(scriptfile: &str) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(scriptfile)
+            .map_err(|e| format!("Failed to read {}: {}", scriptfile, e))?;
+        Script::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, Box<dyn Error>> {
+        let mut steps: Vec<Step> = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(format!("line {}: expected 'time_ms keys pressure'", i + 1).into());
+            }
+            let step = Step {
+                time_ms: fields[0]
+                    .parse()
+                    .map_err(|e| format!("line {}: bad time: {}", i + 1, e))?,
+                keys: fields[1]
+                    .parse::<Keys>()
+                    .map(|keys| keys.0)
+                    .map_err(|e| format!("line {}: bad keys: {}", i + 1, e))?,
+                pressure: fields[2]
+                    .parse()
+                    .map_err(|e| format!("line {}: bad pressure: {}", i + 1, e))?,
+            };
+            if let Some(last) = steps.last() {
+                if step.time_ms < last.time_ms {
+                    return Err(format!("line {}: time goes backwards", i + 1).into());
+                }
+            }
+            steps.push(step);
+        }
+        if steps.is_empty() {
+            return Err("script has no steps".into());
+        }
+        Ok(Script { steps })
+    }
+
+    /// Number of scan ticks needed to play the whole script.
+    pub fn ticks(&self, tick_usecs: u32) -> u64 {
+        let end_ms = self.steps.last().map_or(0, |step| step.time_ms);
+        end_ms as u64 * 1000 / tick_usecs as u64 + 1
+    }
+
+    /// Expand the script into per-tick key and pressure inputs.
+    pub fn inputs(&self, tick_usecs: u32) -> (ScriptedScanner, SimulatedSensor) {
+        let mut keys = Vec::new();
+        let mut pressures = Vec::new();
+        let mut current = 0;
+        for tick in 0..self.ticks(tick_usecs) {
+            let now_us = tick * tick_usecs as u64;
+            while current + 1 < self.steps.len()
+                && self.steps[current + 1].time_ms as u64 * 1000 <= now_us
+            {
+                current += 1;
+            }
+            keys.push(self.steps[current].keys);
+            pressures.push(self.steps[current].pressure);
+        }
+        (
+            ScriptedScanner::new(keys),
+            SimulatedSensor::from_trace(pressures),
+        )
+    }
+}
+
+/// Sink that writes every event as a line of text, timestamped with the
+/// simulated time.
+pub struct EventWriter<W: Write> {
+    out: W,
+    tick_usecs: u32,
+    ticks: u64,
+}
+
+impl<W: Write> EventWriter<W> {
+    pub fn new(out: W, tick_usecs: u32) -> Self {
+        EventWriter {
+            out,
+            tick_usecs,
+            ticks: 0,
+        }
+    }
+
+    fn write(&mut self, event: Event) {
+        // The clock starts at the first tick
+        let elapsed_us = self.ticks.saturating_sub(1) * self.tick_usecs as u64;
+        let _ = writeln!(
+            self.out,
+            "{}.{:03} {}",
+            elapsed_us / 1000,
+            elapsed_us % 1000,
+            event
+        );
+    }
+}
+
+impl<W: Write> SoundSink for EventWriter<W> {
+    fn noteon(&mut self, note: i32, vel: i32) {
+        self.write(Event::NoteOn { note, vel });
+    }
+    fn noteoff(&mut self, note: i32) {
+        self.write(Event::NoteOff { note });
+    }
+    fn cc(&mut self, ctrl: i32, value: i32) {
+        self.write(Event::Cc { ctrl, value });
+    }
+    fn breath(&mut self, value: i32) {
+        self.write(Event::Breath(value));
+    }
+    fn program_change(&mut self, prog: i32) {
+        self.write(Event::ProgramChange(prog));
+    }
+    fn pitch_bend(&mut self, value: i32) {
+        self.write(Event::PitchBend(value));
+    }
+    fn gain(&mut self, gain: f32) {
+        self.write(Event::Gain(gain));
+    }
+    fn pause(&mut self, ms: u64) {
+        self.write(Event::Pause(ms));
+    }
+    fn tick(&mut self) {
+        self.ticks += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::keyscan::KeyScanner;
+    use crate::pressure::BreathSensor;
+
+    #[test]
+    fn parse() -> Result<(), Box<dyn Error>> {
+        let script = Script::parse(
+            "# time keys pressure
+            0 0 0
+            4 0x80 40
+            8 b -12",
+        )?;
+        assert_eq!(script.ticks(2_000), 5);
+        let (mut scanner, mut sensor) = script.inputs(2_000);
+        let mut inputs = Vec::new();
+        for _ in 0..5 {
+            inputs.push((scanner.scan()?, sensor.read()?));
+        }
+        assert_eq!(inputs, vec![(0, 0), (0, 0), (128, 40), (128, 40), (128, -12)]);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Script::parse("").is_err());
+        assert!(Script::parse("0 0").is_err());
+        assert!(Script::parse("0 0xZZ 0").is_err());
+        assert!(Script::parse("0 b+h 0").is_err());
+        assert!(Script::parse("10 0 0\n5 0 0").is_err());
+    }
+
+    #[test]
+    fn writer() {
+        let mut out = Vec::new();
+        {
+            let mut writer = EventWriter::new(&mut out, 2_000);
+            writer.tick();
+            writer.breath(10);
+            writer.tick();
+            writer.noteon(61, 127);
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0.000 breath 10\n2.000 noteon 61 127\n"
+        );
+    }
+}
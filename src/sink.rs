@@ -0,0 +1,267 @@
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+pub const MIDI_CC_VOLUME: i32 = 7;
+
+// Center (no bend) value for 14-bit MIDI pitch bend messages.
+pub const PITCH_BEND_CENTER: i32 = 8192;
+
+/// A single message sent to a sound sink.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    NoteOn { note: i32, vel: i32 },
+    NoteOff { note: i32 },
+    Cc { ctrl: i32, value: i32 },
+    Breath(i32),
+    ProgramChange(i32),
+    PitchBend(i32),
+    Gain(f32),
+    Pause(u64),
+    Feedback(bool),
+}
+
+impl Event {
+    /// Deliver this event to a sink.
+    pub fn apply(&self, sink: &mut dyn SoundSink) {
+        match *self {
+            Event::NoteOn { note, vel } => sink.noteon(note, vel),
+            Event::NoteOff { note } => sink.noteoff(note),
+            Event::Cc { ctrl, value } => sink.cc(ctrl, value),
+            Event::Breath(value) => sink.breath(value),
+            Event::ProgramChange(prog) => sink.program_change(prog),
+            Event::PitchBend(value) => sink.pitch_bend(value),
+            Event::Gain(gain) => sink.gain(gain),
+            Event::Pause(ms) => sink.pause(ms),
+            Event::Feedback(on) => sink.feedback(on),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::NoteOn { note, vel } => write!(f, "noteon {} {}", note, vel),
+            Event::NoteOff { note } => write!(f, "noteoff {}", note),
+            Event::Cc { ctrl, value } => write!(f, "cc {} {}", ctrl, value),
+            Event::Breath(value) => write!(f, "breath {}", value),
+            Event::ProgramChange(prog) => write!(f, "program {}", prog),
+            Event::PitchBend(value) => write!(f, "pitchbend {}", value),
+            Event::Gain(gain) => write!(f, "gain {}", gain),
+            Event::Pause(ms) => write!(f, "pause {}", ms),
+            Event::Feedback(on) => write!(f, "feedback {}", if on { "on" } else { "off" }),
+        }
+    }
+}
+
+/// Anything that can turn notes into sound (or record them).  All messages
+/// go to a single channel.
+pub trait SoundSink {
+    fn noteon(&mut self, note: i32, vel: i32);
+    fn noteoff(&mut self, note: i32);
+    fn cc(&mut self, ctrl: i32, value: i32);
+    fn program_change(&mut self, prog: i32);
+    fn pitch_bend(&mut self, value: i32);
+
+    /// Breath level in the 0-127 range.  Synths use it as channel volume,
+    /// MIDI controllers are expected to send it as a breath controller.
+    fn breath(&mut self, value: i32) {
+        self.cc(MIDI_CC_VOLUME, value);
+    }
+
+    /// Overall volume of synths.  MIDI leaves it to the receiving end.
+    fn gain(&mut self, _gain: f32) {}
+
+    /// Messages between `feedback(true)` and `feedback(false)`, such as beeps,
+    /// are meant for the player only.  Local synths play them, MIDI output
+    /// leaves them out.
+    fn feedback(&mut self, _on: bool) {}
+
+    /// Let the current sound play for a while, e.g. during a beep.
+    fn pause(&mut self, ms: u64) {
+        thread::sleep(Duration::from_millis(ms));
+    }
+
+    /// Called once at the start of every scan tick.  Sinks that timestamp
+    /// their output can use it as a clock.
+    fn tick(&mut self) {}
+}
+
+/// Sink that discards everything.
+pub struct NullSink;
+
+impl SoundSink for NullSink {
+    fn noteon(&mut self, _note: i32, _vel: i32) {}
+    fn noteoff(&mut self, _note: i32) {}
+    fn cc(&mut self, _ctrl: i32, _value: i32) {}
+    fn program_change(&mut self, _prog: i32) {}
+    fn pitch_bend(&mut self, _value: i32) {}
+    fn pause(&mut self, _ms: u64) {}
+}
+
+/// Sink that keeps every event it receives.  Pauses are recorded instead of
+/// slept.
+#[derive(Default)]
+pub struct RecordingSink {
+    pub events: Vec<Event>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        RecordingSink { events: Vec::new() }
+    }
+}
+
+impl SoundSink for RecordingSink {
+    fn noteon(&mut self, note: i32, vel: i32) {
+        self.events.push(Event::NoteOn { note, vel });
+    }
+    fn noteoff(&mut self, note: i32) {
+        self.events.push(Event::NoteOff { note });
+    }
+    fn cc(&mut self, ctrl: i32, value: i32) {
+        self.events.push(Event::Cc { ctrl, value });
+    }
+    fn breath(&mut self, value: i32) {
+        self.events.push(Event::Breath(value));
+    }
+    fn program_change(&mut self, prog: i32) {
+        self.events.push(Event::ProgramChange(prog));
+    }
+    fn pitch_bend(&mut self, value: i32) {
+        self.events.push(Event::PitchBend(value));
+    }
+    fn gain(&mut self, gain: f32) {
+        self.events.push(Event::Gain(gain));
+    }
+    fn pause(&mut self, ms: u64) {
+        self.events.push(Event::Pause(ms));
+    }
+    fn feedback(&mut self, on: bool) {
+        self.events.push(Event::Feedback(on));
+    }
+}
+
+/// Sends every message to several sinks at once.
+#[derive(Default)]
+pub struct FanOut<'a> {
+    sinks: Vec<&'a mut dyn SoundSink>,
+}
+
+impl<'a> FanOut<'a> {
+    pub fn new() -> Self {
+        FanOut { sinks: Vec::new() }
+    }
+
+    pub fn add(&mut self, sink: &'a mut dyn SoundSink) {
+        self.sinks.push(sink);
+    }
+}
+
+impl<'a> SoundSink for FanOut<'a> {
+    fn noteon(&mut self, note: i32, vel: i32) {
+        for sink in self.sinks.iter_mut() {
+            sink.noteon(note, vel);
+        }
+    }
+    fn noteoff(&mut self, note: i32) {
+        for sink in self.sinks.iter_mut() {
+            sink.noteoff(note);
+        }
+    }
+    fn cc(&mut self, ctrl: i32, value: i32) {
+        for sink in self.sinks.iter_mut() {
+            sink.cc(ctrl, value);
+        }
+    }
+    fn breath(&mut self, value: i32) {
+        for sink in self.sinks.iter_mut() {
+            sink.breath(value);
+        }
+    }
+    fn program_change(&mut self, prog: i32) {
+        for sink in self.sinks.iter_mut() {
+            sink.program_change(prog);
+        }
+    }
+    fn pitch_bend(&mut self, value: i32) {
+        for sink in self.sinks.iter_mut() {
+            sink.pitch_bend(value);
+        }
+    }
+    fn gain(&mut self, gain: f32) {
+        for sink in self.sinks.iter_mut() {
+            sink.gain(gain);
+        }
+    }
+    fn feedback(&mut self, on: bool) {
+        for sink in self.sinks.iter_mut() {
+            sink.feedback(on);
+        }
+    }
+    fn pause(&mut self, ms: u64) {
+        // Sinks share the same clock, so only wait once.
+        thread::sleep(Duration::from_millis(ms));
+    }
+    fn tick(&mut self) {
+        for sink in self.sinks.iter_mut() {
+            sink.tick();
+        }
+    }
+}
+
+pub fn beep(sink: &mut dyn SoundSink, note: i32, vol: i32) {
+    sink.feedback(true);
+    sink.noteon(note, vol);
+    sink.breath(vol);
+    sink.pause(100);
+    sink.noteoff(note);
+    sink.feedback(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fan_out() {
+        let mut first = RecordingSink::new();
+        let mut second = RecordingSink::new();
+        {
+            let mut sinks = FanOut::new();
+            sinks.add(&mut first);
+            sinks.add(&mut second);
+            sinks.noteon(60, 127);
+            sinks.breath(40);
+            sinks.noteoff(60);
+        }
+        let expected = vec![
+            Event::NoteOn { note: 60, vel: 127 },
+            Event::Breath(40),
+            Event::NoteOff { note: 60 },
+        ];
+        assert_eq!(first.events, expected);
+        assert_eq!(second.events, expected);
+    }
+
+    #[test]
+    fn replay() {
+        let mut recording = RecordingSink::new();
+        beep(&mut recording, 70, 50);
+        let mut replayed = RecordingSink::new();
+        for event in &recording.events {
+            event.apply(&mut replayed);
+        }
+        assert_eq!(
+            replayed.events,
+            vec![
+                Event::Feedback(true),
+                Event::NoteOn { note: 70, vel: 50 },
+                Event::Breath(50),
+                Event::Pause(100),
+                Event::NoteOff { note: 70 },
+                Event::Feedback(false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,357 @@
+// Settings changed while playing (program, volume, transpose, notemap profile,
+// breath calibration) are kept in a small state file so they survive a restart.  On
+// startup they take precedence over the configuration file, but not over
+// command line flags.
+//
+// The root file system is expected to be read-only, so the state file should
+// live on writable storage.  When it cannot be written, the saved state is
+// still loaded but changes are not kept.  Writes go to a temporary file that
+// is renamed over the old one, so a power cut never leaves a half-written
+// state behind.  They are synced to disk on a thread of their own, away from
+// the play loop.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::calibrate::Calibration;
+use crate::config::{Config, MAX_GAIN};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    pub prog_number: Option<i32>,
+    pub gain: Option<f32>,
+    pub transpose: Option<i32>,
+    pub profile: Option<String>,
+    pub calibration: Option<Calibration>,
+}
+
+impl State {
+    /// Fields of `other` that differ from this state, everything else unset.
+    pub fn changes(&self, other: &State) -> State {
+        fn changed<T: PartialEq + Clone>(old: &Option<T>, new: &Option<T>) -> Option<T> {
+            if old != new {
+                new.clone()
+            } else {
+                None
+            }
+        }
+        State {
+            prog_number: changed(&self.prog_number, &other.prog_number),
+            gain: changed(&self.gain, &other.gain),
+            transpose: changed(&self.transpose, &other.transpose),
+            profile: changed(&self.profile, &other.profile),
+            calibration: changed(&self.calibration, &other.calibration),
+        }
+    }
+
+    // Take over the fields that are set in `other`.
+    fn merge(&mut self, other: &State) {
+        if other.prog_number.is_some() {
+            self.prog_number = other.prog_number;
+        }
+        if other.gain.is_some() {
+            self.gain = other.gain;
+        }
+        if other.transpose.is_some() {
+            self.transpose = other.transpose;
+        }
+        if other.profile.is_some() {
+            self.profile = other.profile.clone();
+        }
+        if other.calibration.is_some() {
+            self.calibration = other.calibration;
+        }
+    }
+
+    /// Override configuration values with the ones saved in the state.  Saved
+    /// values out of range, say from a hand-edited file, are left out.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(prog_number) = self.prog_number {
+            if (0..=127).contains(&prog_number) {
+                config.synth.prog_number = prog_number;
+            } else {
+                warn!("Ignoring saved program {}, not a MIDI program", prog_number);
+            }
+        }
+        if let Some(gain) = self.gain {
+            if (0.0..=MAX_GAIN).contains(&gain) {
+                config.synth.gain = gain;
+            } else {
+                warn!(
+                    "Ignoring saved gain {}, not between 0 and {}",
+                    gain, MAX_GAIN
+                );
+            }
+        }
+        if let Some(transpose) = self.transpose {
+            if (-127..=127).contains(&transpose) {
+                config.notemap.transpose = transpose;
+            } else {
+                warn!(
+                    "Ignoring saved transpose {}, out of the MIDI range",
+                    transpose
+                );
+            }
+        }
+        if let Some(profile) = &self.profile {
+            config.notemap.profile = profile.clone();
+        }
+        if let Some(calibration) = self.calibration {
+            let mut pressure = config.pressure.clone();
+            calibration.apply(&mut pressure);
+            match pressure.validate() {
+                Ok(()) => config.pressure = pressure,
+                Err(e) => warn!("Ignoring saved calibration ({}): {}", calibration, e),
+            }
+        }
+    }
+}
+
+pub struct StateFile {
+    filename: String,
+    state: State,
+    // Thread saving the state, none if the file cannot be written
+    writer: Option<(Sender<State>, JoinHandle<()>)>,
+}
+
+impl StateFile {
+    /// Open the state file.  A missing or unreadable file gives an empty state.
+    pub fn open(statefile: &str) -> Self {
+        let state = match fs::read_to_string(statefile) {
+            Ok(contents) => match toml::from_str(&contents) {
+                Ok(state) => state,
+                Err(e) => {
+                    warn!("Ignoring corrupted state file {}: {}", statefile, e);
+                    State::default()
+                }
+            },
+            Err(_) => {
+                info!("No saved state in {}", statefile);
+                State::default()
+            }
+        };
+        let writer = match check_writable(Path::new(statefile)) {
+            Ok(()) => Some(spawn_writer(statefile)),
+            Err(e) => {
+                warn!(
+                    "State file {} is not writable, changes will not be kept: {}",
+                    statefile, e
+                );
+                None
+            }
+        };
+        StateFile {
+            filename: String::from(statefile),
+            state,
+            writer,
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Record the fields set in `changes`, writing the state out only if
+    /// something actually changed.
+    pub fn update(&mut self, changes: &State) {
+        let mut state = self.state.clone();
+        state.merge(changes);
+        if state == self.state {
+            return;
+        }
+        self.state = state;
+        if let Some((sender, _)) = &self.writer {
+            if sender.send(self.state.clone()).is_err() {
+                warn!("Failed to save state to {}", self.filename);
+            }
+        }
+    }
+}
+
+impl Drop for StateFile {
+    // Let the last changes reach the disk
+    fn drop(&mut self) {
+        if let Some((sender, writer)) = self.writer.take() {
+            drop(sender);
+            let _ = writer.join();
+        }
+    }
+}
+
+// Save every state received, until the sender goes away
+fn spawn_writer(statefile: &str) -> (Sender<State>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::channel::<State>();
+    let filename = String::from(statefile);
+    let writer = thread::spawn(move || {
+        while let Ok(mut state) = receiver.recv() {
+            // Only the latest of the states queued meanwhile matters
+            while let Ok(newer) = receiver.try_recv() {
+                state = newer;
+            }
+            match save(&filename, &state) {
+                Ok(()) => info!("Saved state to {}", filename),
+                Err(e) => warn!("Failed to save state to {}: {}", filename, e),
+            }
+        }
+    });
+    (sender, writer)
+}
+
+fn directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+// Try out the temporary file the state is written to
+fn check_writable(path: &Path) -> io::Result<()> {
+    fs::create_dir_all(directory(path))?;
+    let tmpfile = path.with_extension("tmp");
+    File::create(&tmpfile)?;
+    fs::remove_file(&tmpfile)
+}
+
+fn save(filename: &str, state: &State) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(filename);
+    let dir = directory(path);
+    fs::create_dir_all(dir)?;
+
+    let tmpfile = path.with_extension("tmp");
+    let mut file = File::create(&tmpfile)?;
+    file.write_all(toml::to_string(state)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmpfile, path)?;
+    // Make the rename itself durable
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        const TMP_STATE: &str = "/tmp/haxo-test/state.toml";
+        let _ = fs::remove_file(TMP_STATE);
+        let mut statefile = StateFile::open(TMP_STATE);
+        assert_eq!(statefile.state(), &State::default());
+        statefile.update(&State {
+            prog_number: Some(66),
+            transpose: Some(-9),
+            ..State::default()
+        });
+        statefile.update(&State {
+            prog_number: None,
+            gain: Some(0.8),
+            transpose: Some(-2),
+            profile: Some(String::from("beginner")),
+            calibration: Some(Calibration {
+                baseline: Some(-12),
+                noise_floor: 5,
+                min: 40,
+                max: 500,
+            }),
+        });
+        // Waits for the writes
+        drop(statefile);
+
+        let statefile = StateFile::open(TMP_STATE);
+        assert_eq!(statefile.state().prog_number, Some(66));
+        let mut config = Config::default();
+        statefile.state().apply(&mut config);
+        assert_eq!(config.synth.prog_number, 66);
+        assert_eq!(config.synth.gain, 0.8);
+        assert_eq!(config.notemap.transpose, -2);
+        assert_eq!(config.notemap.profile, "beginner");
+        assert_eq!(config.pressure.ceiling, 500);
+        assert_eq!(config.pressure.baseline, Some(-12));
+    }
+
+    #[test]
+    fn changes() {
+        let old = State {
+            prog_number: Some(66),
+            gain: None,
+            transpose: Some(-14),
+            profile: None,
+            calibration: None,
+        };
+        let new = State {
+            prog_number: Some(67),
+            gain: None,
+            transpose: Some(-14),
+            profile: Some(String::from("standard")),
+            calibration: None,
+        };
+        assert_eq!(
+            old.changes(&new),
+            State {
+                prog_number: Some(67),
+                gain: None,
+                transpose: None,
+                profile: Some(String::from("standard")),
+                calibration: None,
+            }
+        );
+        assert_eq!(old.changes(&old), State::default());
+    }
+
+    #[test]
+    fn partial_state() {
+        let state: State = toml::from_str("transpose = -2").unwrap();
+        let mut config = Config::default();
+        state.apply(&mut config);
+        assert_eq!(config.notemap.transpose, -2);
+        assert_eq!(
+            config.synth.prog_number,
+            Config::default().synth.prog_number
+        );
+    }
+
+    #[test]
+    fn out_of_range() {
+        const TMP_STATE: &str = "/tmp/haxo-test/bad-state.toml";
+        fs::create_dir_all("/tmp/haxo-test").unwrap();
+        fs::write(
+            TMP_STATE,
+            "prog_number = 300
+            gain = 50.0
+            transpose = -9
+
+            [calibration]
+            noise_floor = 10
+            min = 40
+            max = 5000",
+        )
+        .unwrap();
+        let statefile = StateFile::open(TMP_STATE);
+        let mut config = Config::default();
+        statefile.state().apply(&mut config);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.synth, Config::default().synth);
+        assert_eq!(config.pressure, Config::default().pressure);
+        assert_eq!(config.notemap.transpose, -9);
+    }
+
+    #[test]
+    fn not_writable() {
+        let mut statefile = StateFile::open("/proc/haxo/state.toml");
+        statefile.update(&State {
+            transpose: Some(-2),
+            ..State::default()
+        });
+        // Still kept while running
+        assert_eq!(statefile.state().transpose, Some(-2));
+        assert!(fs::metadata("/proc/haxo").is_err());
+    }
+}
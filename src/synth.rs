@@ -2,24 +2,21 @@ extern crate fluidsynth;
 
 use fluidsynth::{audio, midi, settings, synth};
 use log::{info, warn};
-use std::thread;
-use std::time::Duration;
 
 use crate::alsa;
+use crate::config::SynthConfig;
+use crate::sink::SoundSink;
 
-pub fn try_init(
-    sf2file: &str,
-    banknum: i32,
-) -> (synth::Synth, settings::Settings, audio::AudioDriver) {
+pub fn try_init(config: &SynthConfig) -> (synth::Synth, settings::Settings, audio::AudioDriver) {
     let mut settings = settings::Settings::new();
     // try to optimize for low latency
     if settings.setstr("audio.driver", "alsa") {
         warn!("Setting audio.driver in fluidsynth failed");
     }
-    if settings.setint("audio.periods", 3) {
+    if settings.setint("audio.periods", config.periods) {
         warn!("Setting audio.periods in fluidsynth failed");
     }
-    if settings.setint("audio.period-size", 64) {
+    if settings.setint("audio.period-size", config.period_size) {
         warn!("Setting audio.period-size in fluidsynth failed");
     }
 
@@ -36,10 +33,9 @@ pub fn try_init(
     }
     let mut syn = synth::Synth::new(&mut settings);
 
-    const FSYNTH_GAIN: f32 = 1.0;
-    syn.set_gain(FSYNTH_GAIN);
-    if syn.get_gain() != FSYNTH_GAIN {
-        warn!("Failed to set gain to {}", FSYNTH_GAIN);
+    syn.set_gain(config.gain);
+    if syn.get_gain() != config.gain {
+        warn!("Failed to set gain to {}", config.gain);
     }
 
     let adriver = audio::AudioDriver::new(&mut settings, &mut syn);
@@ -71,25 +67,38 @@ pub fn try_init(
     }
 
     // Switch off polyphony for sax
-    if !syn.set_polyphony(1) {
-        warn!("Failed to set polyphony to 1");
+    if !syn.set_polyphony(config.polyphony) {
+        warn!("Failed to set polyphony to {}", config.polyphony);
     }
 
-    let sf2 = syn.sfload(sf2file, 1);
+    let sf2 = syn.sfload(&config.sf2_file, 1);
 
     if sf2 == None {
-        warn!("Failed to load sound font file {}", sf2file);
+        warn!("Failed to load sound font file {}", config.sf2_file);
     }
     // select bank number
-    syn.program_change(0, banknum);
+    syn.program_change(0, config.prog_number);
     println!("Synth created");
     (syn, settings, adriver)
 }
 
-pub fn beep(synth: &synth::Synth, note: i32, vol: i32) {
-    const MIDI_CC_VOLUME: i32 = 7;
-    synth.noteon(0, note, vol);
-    synth.cc(0, MIDI_CC_VOLUME, vol);
-    thread::sleep(Duration::from_millis(100));
-    synth.noteoff(0, note);
+impl SoundSink for synth::Synth {
+    fn noteon(&mut self, note: i32, vel: i32) {
+        synth::Synth::noteon(self, 0, note, vel);
+    }
+    fn noteoff(&mut self, note: i32) {
+        synth::Synth::noteoff(self, 0, note);
+    }
+    fn cc(&mut self, ctrl: i32, value: i32) {
+        synth::Synth::cc(self, 0, ctrl, value);
+    }
+    fn program_change(&mut self, prog: i32) {
+        synth::Synth::program_change(self, 0, prog);
+    }
+    fn pitch_bend(&mut self, value: i32) {
+        synth::Synth::pitch_bend(self, 0, value);
+    }
+    fn gain(&mut self, gain: f32) {
+        synth::Synth::set_gain(self, gain);
+    }
 }
@@ -1,11 +1,9 @@
-use std::thread;
-use std::time::Duration;
-
-use fluidsynth::synth::Synth;
 use log::info;
 
+use crate::config::ThresholdsConfig;
+use crate::keys::Key;
 use crate::notemap::NoteMap;
-use crate::synth::beep;
+use crate::sink::{beep, SoundSink};
 
 #[derive(Copy, Clone, PartialEq)]
 enum TransposeCmd {
@@ -22,25 +20,24 @@ enum TransposeCmd {
 const TRANSPOSE_REFERENCE: i32 = 84;
 
 // Keys used to change transpose by +/- a half step.
-const KEY_R1: u32 = 0x10000;
-const KEY_R3: u32 = 0x400000;
+const KEY_R1: u32 = Key::F.mask();
+const KEY_R3: u32 = Key::D.mask();
 
 // Get the command associated with the given key state and volume.
 // For 'direct' transposition, the note must be played.
-fn get_cmd(key: u32, vol: i32, notemap: &NoteMap) -> TransposeCmd {
+fn get_cmd(key: u32, vol: i32, notemap: &NoteMap, thresholds: &ThresholdsConfig) -> TransposeCmd {
     // Only enable +/- half stepping if neither key is in the notemap.
     let step_ok = notemap.get(&KEY_R1).is_none() && notemap.get(&KEY_R3).is_none();
 
     match (key, notemap.get_untransposed(&key)) {
-        (_, Some(note)) if vol > 10 => TransposeCmd::Direct(note),
+        (_, Some(note)) if vol > thresholds.blow => TransposeCmd::Direct(note),
         (KEY_R1, None) if step_ok => TransposeCmd::HalfStepUp,
         (KEY_R3, None) if step_ok => TransposeCmd::HalfStepDown,
         _ => TransposeCmd::None,
     }
 }
 
-pub(crate) struct Transpose<'a> {
-    synth: &'a Synth,
+pub(crate) struct Transpose {
     // Minimum time keys must be held to compute transpose.
     countdown_init: u32,
 
@@ -52,10 +49,9 @@ pub(crate) struct Transpose<'a> {
     applied: bool,
 }
 
-impl<'a> Transpose<'a> {
-    pub(crate) fn new(synth: &'a Synth, countdown_init: u32) -> Self {
+impl Transpose {
+    pub(crate) fn new(countdown_init: u32) -> Self {
         Transpose {
-            synth,
             countdown_init,
             cmd: TransposeCmd::None,
             countdown: 0,
@@ -63,8 +59,15 @@ impl<'a> Transpose<'a> {
         }
     }
 
-    pub(crate) fn process(self: &mut Self, key: u32, vol: i32, notemap: &mut NoteMap) {
-        let cur_cmd = get_cmd(key, vol, notemap);
+    pub(crate) fn process(
+        self: &mut Self,
+        key: u32,
+        vol: i32,
+        notemap: &mut NoteMap,
+        thresholds: &ThresholdsConfig,
+        sink: &mut dyn SoundSink,
+    ) {
+        let cur_cmd = get_cmd(key, vol, notemap, thresholds);
 
         // When the command changes, restart the countdown.
         if cur_cmd != self.cmd {
@@ -81,32 +84,37 @@ impl<'a> Transpose<'a> {
 
         // Apply the command.
         match self.cmd {
-            TransposeCmd::HalfStepUp => self.offset(1, notemap),
-            TransposeCmd::HalfStepDown => self.offset(-1, notemap),
-            TransposeCmd::Direct(note) => self.direct(note, notemap),
+            TransposeCmd::HalfStepUp => self.offset(1, notemap, sink),
+            TransposeCmd::HalfStepDown => self.offset(-1, notemap, sink),
+            TransposeCmd::Direct(note) => self.direct(note, notemap, sink),
             TransposeCmd::None => (),
         };
         self.applied = true;
     }
 
-    fn set_transpose(self: &mut Self, transpose: i32, notemap: &mut NoteMap) {
+    fn set_transpose(
+        self: &mut Self,
+        transpose: i32,
+        notemap: &mut NoteMap,
+        sink: &mut dyn SoundSink,
+    ) {
         notemap.transpose = transpose;
         info!("Set transpose to {transpose}");
         // Beep reference note then transposed note.
-        beep(&self.synth, TRANSPOSE_REFERENCE, 50);
-        thread::sleep(Duration::from_millis(100));
-        beep(&self.synth, TRANSPOSE_REFERENCE + transpose, 50);
+        beep(sink, TRANSPOSE_REFERENCE, 50);
+        sink.pause(100);
+        beep(sink, TRANSPOSE_REFERENCE + transpose, 50);
     }
 
     // Offset the tranpose amount by a number of half-steps.
-    fn offset(self: &mut Self, offset: i32, notemap: &mut NoteMap) {
-        self.set_transpose(notemap.transpose + offset, notemap);
+    fn offset(self: &mut Self, offset: i32, notemap: &mut NoteMap, sink: &mut dyn SoundSink) {
+        self.set_transpose(notemap.transpose + offset, notemap, sink);
     }
 
     // Jump directly to a transpose, based on the note played.
-    fn direct(self: &mut Self, note: i32, notemap: &mut NoteMap) {
+    fn direct(self: &mut Self, note: i32, notemap: &mut NoteMap, sink: &mut dyn SoundSink) {
         // Compare note to reference to get transpose amount.
         let transpose = note - TRANSPOSE_REFERENCE;
-        self.set_transpose(transpose, notemap);
+        self.set_transpose(transpose, notemap, sink);
     }
 }